@@ -0,0 +1,46 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessLogEntry {
+    pub time: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: i64,
+    pub remote_addr: Option<String>,
+    pub line: String,
+}
+
+// The most recent access log entries, oldest dropped first once full. Workers push
+// concurrently; the lock is only held for a push or a copy of the entries.
+pub struct AccessLogBuffer {
+    capacity: usize,
+    entries: Mutex<VecDeque<AccessLogEntry>>,
+}
+
+impl AccessLogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, entry: AccessLogEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    // Oldest first
+    pub fn snapshot(&self) -> Vec<AccessLogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::fmt;
+use serde_json::{Map, Value};
+use crate::http::{percent_decode, ParseError};
+
+// Parsers turn a raw body into a JSON value, which serde then maps onto the handler's type,
+// so any registered format can feed any Deserialize type
+pub type BodyParser = Box<dyn Fn(&[u8]) -> Result<Value, String> + Send + Sync>;
+
+#[derive(Debug)]
+pub enum BodyError {
+    MissingContentType,
+    UnsupportedMediaType(String),
+    Malformed(String),
+    Read(ParseError),
+}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BodyError::MissingContentType => write!(f, "Missing Content-Type header"),
+            BodyError::UnsupportedMediaType(media_type) => write!(f, "Unsupported media type '{}'", media_type),
+            BodyError::Malformed(message) => write!(f, "Malformed body: {}", message),
+            BodyError::Read(e) => write!(f, "Failed to read body: {:?}", e),
+        }
+    }
+}
+
+impl std::error::Error for BodyError {}
+
+impl From<ParseError> for BodyError {
+    fn from(error: ParseError) -> Self {
+        BodyError::Read(error)
+    }
+}
+
+// Media type to parser, matched case-insensitively and ignoring parameters such as charset
+pub struct BodyParsers {
+    parsers: HashMap<String, BodyParser>,
+}
+
+impl Default for BodyParsers {
+    fn default() -> Self {
+        let mut parsers = BodyParsers { parsers: HashMap::new() };
+        parsers.register("application/json", |body| serde_json::from_slice(body).map_err(|e| e.to_string()));
+        parsers.register("application/x-www-form-urlencoded", parse_form);
+        parsers
+    }
+}
+
+impl BodyParsers {
+    // Replaces any parser already registered for the media type
+    pub fn register<F>(&mut self, media_type: &str, parser: F)
+    where
+        F: Fn(&[u8]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        self.parsers.insert(media_type.to_ascii_lowercase(), Box::new(parser));
+    }
+
+    pub fn parse(&self, content_type: Option<&str>, body: &[u8]) -> Result<Value, BodyError> {
+        let media_type = content_type
+            .map(|value| value.split(';').next().unwrap_or("").trim().to_ascii_lowercase())
+            .filter(|media_type| !media_type.is_empty())
+            .ok_or(BodyError::MissingContentType)?;
+        let parser = self.parsers.get(&media_type)
+            .ok_or(BodyError::UnsupportedMediaType(media_type))?;
+        parser(body).map_err(BodyError::Malformed)
+    }
+}
+
+// `a=1&b=x+y` becomes {"a": "1", "b": "x y"}; values stay strings and a repeated key keeps the last
+fn parse_form(body: &[u8]) -> Result<Value, String> {
+    let body = std::str::from_utf8(body).map_err(|_| "form body is not UTF-8".to_string())?;
+    let mut fields = Map::new();
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        fields.insert(percent_decode(key, true)?, Value::String(percent_decode(value, true)?));
+    }
+    Ok(Value::Object(fields))
+}
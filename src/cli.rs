@@ -0,0 +1,103 @@
+use std::fmt;
+use std::path::PathBuf;
+use crate::config::Config;
+
+pub const USAGE: &str = "Usage: web-server [OPTIONS]
+
+Options:
+  --config <path>      Config file to load (default: config.json)
+  --host <host>        Address to bind, overrides the config file
+  --port <port>        Port to listen on, overrides the config file
+  --workers <count>    Number of worker threads, overrides the config file
+  --log-level <level>  Logging level, overrides the config file
+  --check-config       Validate the configuration and exit without starting the server
+  -h, --help           Print this help";
+
+#[derive(Debug)]
+pub enum CliError {
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::UnknownFlag(flag) => write!(f, "Unknown option '{}'", flag),
+            CliError::MissingValue(flag) => write!(f, "Option '{}' requires a value", flag),
+            CliError::InvalidValue(flag, value) => write!(f, "Invalid value '{}' for '{}'", value, flag),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
+// Command-line overrides; anything left as None keeps the value from the config file
+#[derive(Debug, Default)]
+pub struct CliArgs {
+    pub config_path: Option<PathBuf>,
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub workers: Option<usize>,
+    pub log_level: Option<String>,
+    pub check_config: bool,
+    pub help: bool,
+}
+
+impl CliArgs {
+    // Accepts both `--flag value` and `--flag=value`; `args` excludes the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliArgs, CliError> {
+        let mut parsed = CliArgs::default();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let (flag, inline_value) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => (flag.to_string(), Some(value.to_string())),
+                _ => (arg, None),
+            };
+
+            if flag == "-h" || flag == "--help" {
+                parsed.help = true;
+                continue;
+            }
+            if flag == "--check-config" {
+                parsed.check_config = true;
+                continue;
+            }
+            if !matches!(flag.as_str(), "--config" | "--host" | "--port" | "--workers" | "--log-level") {
+                return Err(CliError::UnknownFlag(flag));
+            }
+
+            let value = match inline_value.or_else(|| args.next()) {
+                Some(value) => value,
+                None => return Err(CliError::MissingValue(flag)),
+            };
+            let invalid = || CliError::InvalidValue(flag.clone(), value.clone());
+
+            match flag.as_str() {
+                "--config" => parsed.config_path = Some(PathBuf::from(&value)),
+                "--host" => parsed.host = Some(value.clone()),
+                "--port" => parsed.port = Some(value.parse().map_err(|_| invalid())?),
+                "--workers" => parsed.workers = Some(value.parse().map_err(|_| invalid())?),
+                _ => parsed.log_level = Some(value.clone()),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(host) = &self.host {
+            config.host = host.clone();
+        }
+        if let Some(port) = self.port {
+            config.port = port;
+        }
+        if let Some(workers) = self.workers {
+            config.workers = workers;
+        }
+        if let Some(log_level) = &self.log_level {
+            config.log_level = log_level.clone();
+        }
+    }
+}
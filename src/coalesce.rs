@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use crate::http::Response;
+
+#[derive(Default)]
+struct Flight {
+    // Outer None while the leader runs; Some(None) if its response could not be shared
+    result: Mutex<Option<Option<Response>>>,
+    done: Condvar,
+}
+
+// Single-flight: while a request for a key is being handled, identical requests wait for
+// that response instead of running the handler again
+#[derive(Default)]
+pub struct SingleFlight {
+    in_flight: Mutex<HashMap<String, Arc<Flight>>>,
+}
+
+impl SingleFlight {
+    pub fn run<F>(&self, key: &str, handler: F) -> Response
+    where
+        F: FnOnce() -> Response,
+    {
+        let (flight, leader) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(key) {
+                Some(flight) => (Arc::clone(flight), false),
+                None => {
+                    let flight = Arc::new(Flight::default());
+                    in_flight.insert(key.to_string(), Arc::clone(&flight));
+                    (flight, true)
+                }
+            }
+        };
+
+        if leader {
+            let mut publish = Publish { owner: self, key, flight: &flight, shared: None };
+            let response = handler();
+            publish.shared = response.try_clone();
+            return response;
+        }
+
+        let mut result = flight.result.lock().unwrap();
+        while result.is_none() {
+            result = flight.done.wait(result).unwrap();
+        }
+        match result.as_ref().and_then(|shared| shared.as_ref()).and_then(Response::try_clone) {
+            Some(response) => response,
+            // Streamed responses cannot be replayed and a panicking leader leaves nothing
+            None => {
+                drop(result);
+                handler()
+            }
+        }
+    }
+}
+
+// Wakes the waiters when the leader finishes, including by panicking
+struct Publish<'a> {
+    owner: &'a SingleFlight,
+    key: &'a str,
+    flight: &'a Flight,
+    shared: Option<Response>,
+}
+
+impl Drop for Publish<'_> {
+    fn drop(&mut self) {
+        // Later arrivals start a fresh flight rather than reuse this response
+        self.owner.in_flight.lock().unwrap().remove(self.key);
+        *self.flight.result.lock().unwrap() = Some(self.shared.take());
+        self.flight.done.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    const CALLERS: usize = 8;
+
+    // Runs `handler` for CALLERS threads released at once; returns the bodies they got
+    fn run_concurrently<F>(flight: &Arc<SingleFlight>, key: &'static str, handler: F) -> Vec<Vec<u8>>
+    where
+        F: Fn() -> Response + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        let barrier = Arc::new(Barrier::new(CALLERS));
+        let callers: Vec<_> = (0..CALLERS)
+            .map(|_| {
+                let (flight, handler, barrier) = (Arc::clone(flight), Arc::clone(&handler), Arc::clone(&barrier));
+                thread::spawn(move || {
+                    barrier.wait();
+                    flight.run(key, || handler()).body
+                })
+            })
+            .collect();
+        callers.into_iter().map(|caller| caller.join().unwrap()).collect()
+    }
+
+    #[test]
+    fn concurrent_identical_requests_run_the_handler_once() {
+        let flight = Arc::new(SingleFlight::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let bodies = run_concurrently(&flight, "GET /report", move || {
+            let call = counted.fetch_add(1, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(200));
+            Response::ok("text/plain", format!("report {}", call).into_bytes())
+        });
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(bodies.iter().all(|body| body == b"report 0"));
+        // The flight is over, so the next request runs the handler again
+        assert!(flight.in_flight.lock().unwrap().is_empty());
+        assert_eq!(flight.run("GET /report", || Response::ok("text/plain", b"fresh".to_vec())).body, b"fresh");
+    }
+
+    #[test]
+    fn different_keys_do_not_share() {
+        let flight = SingleFlight::default();
+        let first = flight.run("GET /a", || Response::ok("text/plain", b"a".to_vec()));
+        let second = flight.run("GET /b", || Response::ok("text/plain", b"b".to_vec()));
+        assert_eq!((first.body, second.body), (b"a".to_vec(), b"b".to_vec()));
+    }
+
+    #[test]
+    fn waiters_run_the_handler_themselves_when_the_leader_panics() {
+        let flight = Arc::new(SingleFlight::default());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let leader_done = Arc::new(Barrier::new(2));
+        let waiter_ready = Arc::clone(&leader_done);
+
+        let leader = {
+            let flight = Arc::clone(&flight);
+            thread::spawn(move || {
+                flight.run("GET /flaky", || {
+                    leader_done.wait();
+                    thread::sleep(Duration::from_millis(100));
+                    panic!("leader failed")
+                })
+            })
+        };
+        waiter_ready.wait();
+        let response = flight.run("GET /flaky", move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+            Response::ok("text/plain", b"recovered".to_vec())
+        });
+
+        assert!(leader.join().is_err());
+        assert_eq!(response.body, b"recovered");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
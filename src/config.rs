@@ -1,14 +1,260 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::net::IpAddr;
 use std::path::Path;
+use std::time::Duration;
+use crate::threadpool::RejectionPolicy;
+use crate::http::{Method, DEFAULT_MAX_CHUNK_SIZE, DEFAULT_MAX_QUERY_PARAMS};
+use crate::proxy::Upstream;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug)]
+pub enum ConfigError {
+    NotFound,
+    IoError(io::Error),
+    ParseError(serde_json::Error),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::NotFound => write!(f, "Config file not found"),
+            ConfigError::IoError(e) => write!(f, "Failed to read config file: {}", e),
+            ConfigError::ParseError(e) => write!(f, "Failed to parse config file: {}", e),
+            ConfigError::Invalid(problems) => write!(f, "Invalid configuration: {}", problems.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::NotFound {
+            ConfigError::NotFound
+        } else {
+            ConfigError::IoError(error)
+        }
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(error: serde_json::Error) -> Self {
+        ConfigError::ParseError(error)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub host: String,
     pub port: u16,
     pub workers: usize,
+    pub worker_max_jobs: Option<usize>,
+    pub queue_capacity: Option<usize>,
+    pub rejection_policy: RejectionPolicy,
+    pub shed_when_saturated: bool,
     pub static_dir: Option<String>,
+    pub base_path: String,
+    pub max_query_length: usize,
+    pub max_query_params: usize,
     pub log_level: String,
+    pub log_format: Option<String>,
+    pub slow_request_threshold_ms: Option<u64>,
+    pub access_log_buffer: Option<usize>,
+    pub tracing: bool,
+    pub response_time_header: Option<String>,
+    pub read_timeout_secs: Option<u64>,
+    pub keep_alive_timeout_secs: u64,
+    pub max_requests_per_connection: usize,
+    pub shutdown_timeout_secs: u64,
+    pub accept_error_backoff_ms: u64,
+    pub max_response_headers: usize,
+    pub max_response_header_size: usize,
+    pub write_timeout_secs: Option<u64>,
+    pub max_concurrent_per_ip: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    pub reject_during_recovery: bool,
+    pub warmup_seconds: u64,
+    pub max_body_size: usize,
+    pub max_chunk_size: usize,
+    pub body_size_limits: HashMap<String, usize>,
+    pub lazy_body: bool,
+    pub upload_spool_threshold: Option<usize>,
+    pub body_policy: BodyPolicyConfig,
+    pub metrics_auth: MetricsAuthConfig,
+    pub expose_metrics_on_home: bool,
+    pub admin_token: Option<String>,
+    pub cors: Option<CorsConfig>,
+    pub proxies: HashMap<String, String>,
+    pub upstream_pool: UpstreamPoolConfig,
+    pub rewrites: Vec<RewriteRule>,
+    pub robots_txt: Option<String>,
+    pub sitemap_xml: Option<String>,
+    pub default_headers: HashMap<String, String>,
+    pub security_headers: SecurityHeadersConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BodyPolicyConfig {
+    pub require_content_type: Vec<String>,
+    pub require_length: Vec<String>,
+    pub forbid_body: Vec<String>,
+}
+
+impl Default for BodyPolicyConfig {
+    fn default() -> Self {
+        Self {
+            require_content_type: vec!["POST".to_string()],
+            require_length: vec!["POST".to_string(), "PUT".to_string(), "PATCH".to_string()],
+            forbid_body: Vec::new(),
+        }
+    }
+}
+
+impl BodyPolicyConfig {
+    pub fn applies(methods: &[String], method: &str) -> bool {
+        methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+}
+
+// Leaving both fields empty keeps the metrics endpoints open
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsAuthConfig {
+    pub token: Option<String>,
+    pub allowed_ips: Vec<IpAddr>,
+}
+
+// `from` is a path pattern where each `*` captures any run of characters; the captures are
+// substituted for `$1`, `$2`, ... in `to`. `/v1/*` -> `/$1` strips a prefix, `/*` -> `/v2/$1`
+// adds one, and `/users/*/posts/*` -> `/posts/$2?user=$1` reorders segments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RewriteRule {
+    pub from: String,
+    pub to: String,
+    // Answer with this redirect status instead of rewriting internally
+    #[serde(default)]
+    pub redirect: Option<u16>,
+}
+
+impl RewriteRule {
+    pub fn apply(&self, path: &str) -> Option<String> {
+        let captures = match_pattern(&self.from, path)?;
+        let mut target = self.to.clone();
+        // Highest index first so `$1` does not clobber the start of `$10`
+        for (i, capture) in captures.iter().enumerate().rev() {
+            target = target.replace(&format!("${}", i + 1), capture);
+        }
+        Some(target)
+    }
+}
+
+// Each `*` matches as little as possible, except a trailing one which takes the rest
+fn match_pattern<'a>(pattern: &str, path: &'a str) -> Option<Vec<&'a str>> {
+    let mut literals = pattern.split('*');
+    let mut rest = path.strip_prefix(literals.next().unwrap_or(""))?;
+    let mut captures = Vec::new();
+    let literals: Vec<&str> = literals.collect();
+
+    for (i, literal) in literals.iter().enumerate() {
+        let end = if i + 1 == literals.len() {
+            // The final literal has to end the path
+            if !rest.ends_with(literal) {
+                return None;
+            }
+            rest.len() - literal.len()
+        } else if literal.is_empty() {
+            0
+        } else {
+            rest.find(literal)?
+        };
+        captures.push(&rest[..end]);
+        rest = &rest[end + literal.len()..];
+    }
+
+    rest.is_empty().then_some(captures)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub max_age_seconds: Option<u64>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec!["*".to_string()],
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "OPTIONS".to_string()],
+            allowed_headers: vec!["Content-Type".to_string()],
+            max_age_seconds: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UpstreamPoolConfig {
+    pub max_idle: usize,
+    pub min_idle: usize,
+    pub idle_timeout_secs: u64,
+    pub health_check_interval_secs: u64,
+}
+
+impl Default for UpstreamPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 8,
+            min_idle: 0,
+            idle_timeout_secs: 60,
+            health_check_interval_secs: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SecurityHeadersConfig {
+    pub content_type_options: Option<String>,
+    pub frame_options: Option<String>,
+    pub xss_protection: Option<String>,
+    pub content_security_policy: Option<String>,
+    pub strict_transport_security: Option<String>,
+}
+
+impl Default for SecurityHeadersConfig {
+    fn default() -> Self {
+        Self {
+            content_type_options: Some("nosniff".to_string()),
+            frame_options: Some("DENY".to_string()),
+            xss_protection: Some("1; mode=block".to_string()),
+            content_security_policy: None,
+            strict_transport_security: None,
+        }
+    }
+}
+
+impl SecurityHeadersConfig {
+    pub fn headers(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("X-Content-Type-Options", &self.content_type_options),
+            ("X-Frame-Options", &self.frame_options),
+            ("X-XSS-Protection", &self.xss_protection),
+            ("Content-Security-Policy", &self.content_security_policy),
+            ("Strict-Transport-Security", &self.strict_transport_security),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.as_deref().map(|v| (name, v)))
+        .collect()
+    }
 }
 
 impl Default for Config {
@@ -17,19 +263,180 @@ impl Default for Config {
             host: "127.0.0.1".to_string(),
             port: 7878,
             workers: 4,
+            worker_max_jobs: None,
+            queue_capacity: None,
+            rejection_policy: RejectionPolicy::default(),
+            shed_when_saturated: false,
             static_dir: None,
+            base_path: String::new(),
+            max_query_length: 4096,
+            max_query_params: DEFAULT_MAX_QUERY_PARAMS,
             log_level: "info".to_string(),
+            log_format: None,
+            slow_request_threshold_ms: None,
+            access_log_buffer: None,
+            tracing: false,
+            response_time_header: None,
+            read_timeout_secs: Some(30),
+            keep_alive_timeout_secs: 5,
+            max_requests_per_connection: 100,
+            shutdown_timeout_secs: 30,
+            accept_error_backoff_ms: 100,
+            max_response_headers: 200,
+            max_response_header_size: 64 * 1024,
+            write_timeout_secs: Some(30),
+            max_concurrent_per_ip: None,
+            max_connections_per_ip: None,
+            reject_during_recovery: false,
+            warmup_seconds: 0,
+            max_body_size: 1024 * 1024 * 10,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            body_size_limits: HashMap::new(),
+            lazy_body: false,
+            upload_spool_threshold: None,
+            body_policy: BodyPolicyConfig::default(),
+            metrics_auth: MetricsAuthConfig::default(),
+            expose_metrics_on_home: true,
+            admin_token: None,
+            cors: None,
+            proxies: HashMap::new(),
+            upstream_pool: UpstreamPoolConfig::default(),
+            rewrites: Vec::new(),
+            robots_txt: None,
+            sitemap_xml: None,
+            default_headers: HashMap::new(),
+            security_headers: SecurityHeadersConfig::default(),
         }
     }
 }
 
 impl Config {
-    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
         let contents = fs::read_to_string(path)?;
         let config: Config = serde_json::from_str(&contents)?;
         Ok(config)
     }
 
+    // Checks values that deserialize fine but cannot work at runtime, collecting every problem
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.host.is_empty() {
+            problems.push("host must not be empty".to_string());
+        }
+        if self.workers == 0 {
+            problems.push("workers must be greater than 0".to_string());
+        }
+        if self.max_requests_per_connection == 0 {
+            problems.push("max_requests_per_connection must be greater than 0".to_string());
+        }
+        if self.max_response_headers == 0 || self.max_response_header_size == 0 {
+            problems.push("max_response_headers and max_response_header_size must be greater than 0".to_string());
+        }
+        if self.max_chunk_size == 0 {
+            problems.push("max_chunk_size must be greater than 0".to_string());
+        }
+        if self.worker_max_jobs == Some(0) {
+            problems.push("worker_max_jobs must be greater than 0".to_string());
+        }
+        if !self.base_path.is_empty() && !self.base_path.starts_with('/') {
+            problems.push(format!("base_path '{}' must start with '/'", self.base_path));
+        }
+        if let Some(dir) = &self.static_dir {
+            if !Path::new(dir).is_dir() {
+                problems.push(format!("static_dir '{}' is not a directory", dir));
+            }
+        }
+        for prefix in self.body_size_limits.keys() {
+            if !prefix.starts_with('/') {
+                problems.push(format!("body_size_limits key '{}' must start with '/'", prefix));
+            }
+        }
+
+        let policy = &self.body_policy;
+        for method in policy.require_content_type.iter().chain(&policy.require_length).chain(&policy.forbid_body) {
+            if !Method::ALL.iter().any(|m| m.as_str().eq_ignore_ascii_case(method)) {
+                problems.push(format!("body_policy lists unknown method '{}'", method));
+            }
+        }
+
+        for (prefix, upstream) in &self.proxies {
+            if !prefix.starts_with('/') {
+                problems.push(format!("proxies key '{}' must start with '/'", prefix));
+            }
+            if let Err(e) = Upstream::parse(upstream) {
+                problems.push(e);
+            }
+        }
+        let pool = &self.upstream_pool;
+        if pool.min_idle > pool.max_idle {
+            problems.push("upstream_pool.min_idle must not exceed max_idle".to_string());
+        }
+        if pool.health_check_interval_secs == 0 {
+            problems.push("upstream_pool.health_check_interval_secs must be greater than 0".to_string());
+        }
+
+        for rule in &self.rewrites {
+            if !rule.from.starts_with('/') {
+                problems.push(format!("rewrite '{}' must start with '/'", rule.from));
+            }
+            if !rule.to.starts_with('/') && rule.redirect.is_none() {
+                problems.push(format!("rewrite target '{}' must start with '/'", rule.to));
+            }
+            if rule.redirect.is_some_and(|code| ![301, 302, 303, 307, 308].contains(&code)) {
+                problems.push(format!("rewrite '{}' has a non-redirect status", rule.from));
+            }
+        }
+
+        if self.cors.as_ref().is_some_and(|cors| cors.allowed_origins.is_empty()) {
+            problems.push("cors.allowed_origins must not be empty".to_string());
+        }
+
+        // Header values end up verbatim in responses, so line breaks would allow header injection
+        let header_values = self.default_headers.iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(self.security_headers.headers());
+        for (name, value) in header_values {
+            if name.contains(['\r', '\n', ':']) || value.contains(['\r', '\n']) {
+                problems.push(format!("header '{}' contains invalid characters", name.escape_debug()));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    // None (or 0) leaves reads without a timeout
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout_secs.filter(|s| *s > 0).map(Duration::from_secs)
+    }
+
+    // The longest matching path prefix in `body_size_limits` overrides `max_body_size`
+    pub fn max_body_size_for(&self, path: &str) -> usize {
+        self.body_size_limits
+            .iter()
+            .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.max_body_size, |(_, limit)| *limit)
+    }
+
+    // Maps a path under `base_path` to its route, e.g. "/app/health" -> "/health".
+    // Paths outside the prefix are left alone so a proxy that already strips it still works.
+    pub fn strip_base_path<'a>(&self, path: &'a str) -> &'a str {
+        let base = self.base_path.trim_end_matches('/');
+        if base.is_empty() {
+            return path;
+        }
+        match path.strip_prefix(base) {
+            Some("") => "/",
+            Some(rest) if rest.starts_with('/') => rest,
+            _ => path,
+        }
+    }
+
     pub fn address(&self) -> String {
         format!("{}:{}", self.host, self.port)
     }
@@ -0,0 +1,83 @@
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthStatus {
+    Healthy,
+    Unhealthy(String),
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, HealthStatus::Healthy)
+    }
+}
+
+type HealthCheckFn = Box<dyn Fn() -> HealthStatus + Send + Sync>;
+
+struct HealthCheck {
+    name: String,
+    // A failing critical check makes the whole server report unhealthy (503)
+    critical: bool,
+    check: HealthCheckFn,
+}
+
+#[derive(Default)]
+pub struct HealthRegistry {
+    checks: Vec<HealthCheck>,
+}
+
+pub struct HealthReport {
+    pub healthy: bool,
+    pub body: Value,
+}
+
+impl HealthRegistry {
+    pub fn register<F>(&mut self, name: &str, critical: bool, check: F)
+    where
+        F: Fn() -> HealthStatus + Send + Sync + 'static,
+    {
+        self.checks.push(HealthCheck {
+            name: name.to_string(),
+            critical,
+            check: Box::new(check),
+        });
+    }
+
+    // Runs every check; only critical failures make the report unhealthy,
+    // non-critical ones downgrade the overall status to "degraded"
+    pub fn report(&self) -> HealthReport {
+        let mut checks = serde_json::Map::new();
+        let mut critical_failed = false;
+        let mut any_failed = false;
+
+        for entry in &self.checks {
+            let status = (entry.check)();
+            if !status.is_healthy() {
+                any_failed = true;
+                critical_failed |= entry.critical;
+            }
+            let detail = match &status {
+                HealthStatus::Healthy => json!({ "status": "healthy", "critical": entry.critical }),
+                HealthStatus::Unhealthy(reason) => json!({
+                    "status": "unhealthy",
+                    "critical": entry.critical,
+                    "detail": reason,
+                }),
+            };
+            checks.insert(entry.name.clone(), detail);
+        }
+
+        let status = if critical_failed {
+            "unhealthy"
+        } else if any_failed {
+            "degraded"
+        } else {
+            "healthy"
+        };
+
+        HealthReport {
+            healthy: !critical_failed,
+            body: json!({ "status": status, "checks": checks }),
+        }
+    }
+}
@@ -1,11 +1,79 @@
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::io::{self, Read, ErrorKind};
+use std::fmt;
+use std::collections::hash_map::RandomState;
+use std::io::{self, Read, Write, ErrorKind};
+use std::net::{SocketAddr, TcpStream};
+use std::num::IntErrorKind;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use crate::template::html_escape;
+use crate::spool::{BodyBuffer, RequestBody};
+use crate::body_parser::{BodyError, BodyParsers};
 
 const MAX_HEADER_SIZE: usize = 8192; // 8KB
+const DEFAULT_MAX_BODY_SIZE: usize = 1024 * 1024 * 10; // 10MB
+pub const DEFAULT_MAX_CHUNK_SIZE: usize = 1024 * 1024; // 1MB
+pub const DEFAULT_MAX_QUERY_PARAMS: usize = 256;
+// A chunk size line with extensions, or one trailer field
+const MAX_CHUNK_LINE_SIZE: usize = MAX_HEADER_SIZE;
+const BODY_READ_CHUNK_SIZE: usize = 8192;
 const MAX_READ_RETRIES: u32 = 3;
 const RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_POOLED_HEADER_BUFFERS: usize = 64;
+const MAX_HEADER_COUNT: usize = 100;
+// IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+// Header names are attacker controlled, so request maps spell out std's randomly keyed
+// SipHash rather than relying on the default or switching to a faster unkeyed hasher
+pub type HeaderMap = HashMap<String, String, RandomState>;
+// Keys come from the client too, so the query map is keyed the same way
+pub type QueryMap = HashMap<String, String, RandomState>;
+
+// Header buffers are reused across requests instead of allocating 8KB per request.
+// Parsing only reads back the bytes written for the current request, so stale
+// contents from an earlier request are never observed.
+static HEADER_BUFFERS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+struct HeaderBuffer(Vec<u8>);
+
+impl HeaderBuffer {
+    fn checkout() -> Self {
+        let pooled = HEADER_BUFFERS.lock().ok().and_then(|mut pool| pool.pop());
+        HeaderBuffer(pooled.unwrap_or_else(|| vec![0; MAX_HEADER_SIZE]))
+    }
+}
+
+impl Deref for HeaderBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for HeaderBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl Drop for HeaderBuffer {
+    fn drop(&mut self) {
+        if let Ok(mut pool) = HEADER_BUFFERS.lock() {
+            if pool.len() < MAX_POOLED_HEADER_BUFFERS {
+                pool.push(std::mem::take(&mut self.0));
+            }
+        }
+    }
+}
 
 #[derive(Debug, Clone, Eq, Hash, PartialEq)]
 pub enum Method {
@@ -18,25 +86,47 @@ pub enum Method {
     PATCH,
 }
 
-impl From<&str> for Method {
-    fn from(s: &str) -> Self {
-        match s.to_uppercase().as_str() {
-            "GET" => Method::GET,
-            "POST" => Method::POST,
-            "PUT" => Method::PUT,
-            "DELETE" => Method::DELETE,
-            "HEAD" => Method::HEAD,
-            "OPTIONS" => Method::OPTIONS,
-            "PATCH" => Method::PATCH,
-            _ => Method::GET // Default  GET
+impl Method {
+    pub const ALL: [Method; 7] = [
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PUT,
+        Method::DELETE,
+        Method::PATCH,
+        Method::OPTIONS,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::GET => "GET",
+            Method::POST => "POST",
+            Method::PUT => "PUT",
+            Method::DELETE => "DELETE",
+            Method::HEAD => "HEAD",
+            Method::OPTIONS => "OPTIONS",
+            Method::PATCH => "PATCH",
         }
     }
+
+    // None for verbs this server does not implement, which are answered with 501
+    pub fn parse(s: &str) -> Option<Method> {
+        Method::ALL.into_iter().find(|m| m.as_str().eq_ignore_ascii_case(s))
+    }
 }
 
 #[derive(Debug)]
 pub enum ParseError {
     InvalidRequest,
     ContentTooLarge,
+    TooManyHeaders,
+    // The request line alone fills the head buffer
+    UriTooLong,
+    // More query parameters than the caller allows
+    TooManyQueryParams,
+    UnsupportedVersion,
+    UnsupportedMethod,
+    UnsupportedTransferCoding,
     IoError(io::Error),
 }
 
@@ -46,24 +136,345 @@ impl From<io::Error> for ParseError {
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct ParseLimits {
+    pub max_body_size: usize,
+    // Largest single chunk accepted in a chunked body
+    pub max_chunk_size: usize,
+    // Bodies larger than this are written to a temp file instead of kept in memory
+    pub spool_threshold: Option<usize>,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            max_chunk_size: DEFAULT_MAX_CHUNK_SIZE,
+            spool_threshold: None,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Request {
     pub method: Method,
+    // The target without its query string, which is split out into `query`
     pub path: String,
-    pub headers: HashMap<String, String>,
-    pub body: Vec<u8>,
+    // Percent-decoded query parameters; for a repeated key the last value wins, and
+    // `query_all` returns every value. `?debug` maps "debug" to "".
+    pub query: QueryMap,
+    raw_query: Option<String>,
+    pub version: String,
+    pub headers: HeaderMap,
+    pub body: RequestBody,
+    pub remote_addr: Option<SocketAddr>,
+    // Values for the `:name` segments of the matched route, filled in at dispatch
+    pub params: HashMap<String, String>,
+    pub extensions: Extensions,
+    deferred_body: RefCell<Option<(TcpStream, ParseLimits)>>,
+    // The client socket, for interim responses and disconnect checks
+    connection: Option<TcpStream>,
+}
+
+// Per-request values keyed by type, for middleware to hand data to handlers and to later hooks
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn insert<T: Any + Send + Sync>(&mut self, value: T) -> Option<T> {
+        self.map.insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok().map(|boxed| *boxed))
+    }
+
+    pub fn get<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn remove<T: Any + Send + Sync>(&mut self) -> Option<T> {
+        self.map.remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|boxed| *boxed))
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+// The two forms of Retry-After: a delay in seconds or an HTTP-date
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAfter {
+    Seconds(u64),
+    Date(DateTime<Utc>),
+}
+
+impl RetryAfter {
+    // Rounds partial seconds up so clients never retry early
+    pub fn after(delay: Duration) -> RetryAfter {
+        RetryAfter::Seconds(delay.as_secs() + u64::from(delay.subsec_nanos() > 0))
+    }
+
+    pub fn parse(value: &str) -> Option<RetryAfter> {
+        let value = value.trim();
+        if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+            return value.parse().ok().map(RetryAfter::Seconds);
+        }
+        DateTime::parse_from_rfc2822(value)
+            .ok()
+            .map(|date| RetryAfter::Date(date.with_timezone(&Utc)))
+    }
+
+    pub fn to_header_value(self) -> String {
+        match self {
+            RetryAfter::Seconds(seconds) => seconds.to_string(),
+            RetryAfter::Date(date) => date.format(HTTP_DATE_FORMAT).to_string(),
+        }
+    }
+
+    // How long to wait from now; dates in the past mean retry immediately
+    pub fn delay(self) -> Duration {
+        match self {
+            RetryAfter::Seconds(seconds) => Duration::from_secs(seconds),
+            RetryAfter::Date(date) => (date - Utc::now()).to_std().unwrap_or(Duration::ZERO),
+        }
+    }
+}
+
+enum ByteRange {
+    // Inclusive, already clamped to the body
+    Satisfiable(usize, usize),
+    Unsatisfiable,
+    Ignored,
+}
+
+// `first-last`, `first-` or `-suffix_length` against a body of `len` bytes
+fn parse_byte_range(spec: &str, len: usize) -> ByteRange {
+    if spec.contains(',') {
+        return ByteRange::Ignored;
+    }
+    let Some((first, last)) = spec.split_once('-') else {
+        return ByteRange::Ignored;
+    };
+    let number = |s: &str| (!s.is_empty() && s.bytes().all(|b| b.is_ascii_digit())).then(|| s.parse::<usize>().ok()).flatten();
+
+    match (first.trim(), last.trim()) {
+        ("", suffix) => match number(suffix) {
+            Some(0) => ByteRange::Unsatisfiable,
+            Some(_) if len == 0 => ByteRange::Unsatisfiable,
+            Some(suffix) => ByteRange::Satisfiable(len.saturating_sub(suffix), len - 1),
+            None => ByteRange::Ignored,
+        },
+        (first, last) => {
+            let Some(start) = number(first) else {
+                return ByteRange::Ignored;
+            };
+            let end = match last {
+                "" => usize::MAX,
+                last => match number(last) {
+                    Some(end) if end >= start => end,
+                    _ => return ByteRange::Ignored,
+                },
+            };
+            if start >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Satisfiable(start, end.min(len - 1))
+            }
+        }
+    }
 }
 
+// The standard reason phrase for a status code, or "" for codes without one
+pub fn reason_phrase(status_code: u16) -> &'static str {
+    match status_code {
+        100 => "Continue",
+        101 => "Switching Protocols",
+        103 => "Early Hints",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        411 => "Length Required",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        416 => "Range Not Satisfiable",
+        422 => "Unprocessable Content",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+pub type StreamBody = Box<dyn FnOnce(&mut ChunkedWriter<'_>) -> io::Result<()> + Send>;
+
 pub struct Response {
     pub status_code: u16,
     pub status_text: String,
     pub headers: HashMap<String, String>,
+    // Further lines for names already in `headers`, for fields such as Set-Cookie whose
+    // values cannot be joined into one line; see Response::append_header
+    appended_headers: Vec<(String, String)>,
     pub body: Vec<u8>,
+    // When set, the body is produced by this callback and sent with chunked encoding
+    pub stream: Option<StreamBody>,
+    // Applied in order as the body is written; see Response::push_transform
+    transforms: Vec<Box<dyn BodyTransform>>,
+}
+
+// A streaming body filter such as compression or minification. Each write's bytes go through
+// `transform`, and `finish` emits anything held back once the body is complete.
+pub trait BodyTransform: Send {
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()>;
+
+    fn finish(&mut self, _output: &mut Vec<u8>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Stateless per-chunk filters can be plain closures
+impl<F> BodyTransform for F
+where
+    F: FnMut(&[u8], &mut Vec<u8>) -> io::Result<()> + Send,
+{
+    fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        self(input, output)
+    }
+}
+
+// Frames each write as one chunk; trailers added during streaming follow the final chunk.
+// Output is buffered: call flush() to push what has been written so far to the client.
+pub struct ChunkedWriter<'a> {
+    inner: &'a mut dyn Write,
+    trailers: Vec<(String, String)>,
+    // False for bodies framed by Content-Length, which are written through unchanged
+    chunked: bool,
+    // Run on the payload before it is framed
+    transforms: Vec<Box<dyn BodyTransform>>,
+}
+
+impl<'a> ChunkedWriter<'a> {
+    pub fn new(inner: &'a mut dyn Write) -> Self {
+        Self { inner, trailers: Vec::new(), chunked: true, transforms: Vec::new() }
+    }
+
+    pub fn identity(inner: &'a mut dyn Write) -> Self {
+        Self { inner, trailers: Vec::new(), chunked: false, transforms: Vec::new() }
+    }
+
+    pub fn with_transforms(mut self, transforms: Vec<Box<dyn BodyTransform>>) -> Self {
+        self.transforms = transforms;
+        self
+    }
+
+    // When finishing, each transform's held-back output still passes through the later ones
+    fn run_transforms(&mut self, input: &[u8], finish: bool) -> io::Result<Vec<u8>> {
+        let mut data = input.to_vec();
+        for transform in &mut self.transforms {
+            let mut output = Vec::new();
+            if !data.is_empty() {
+                transform.transform(&data, &mut output)?;
+            }
+            if finish {
+                transform.finish(&mut output)?;
+            }
+            data = output;
+        }
+        Ok(data)
+    }
+
+    fn write_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        // A zero-length chunk would terminate the body early
+        if payload.is_empty() {
+            return Ok(());
+        }
+        if !self.chunked {
+            return self.inner.write_all(payload);
+        }
+        let mut chunk = format!("{:x}\r\n", payload.len()).into_bytes();
+        chunk.extend_from_slice(payload);
+        chunk.extend_from_slice(b"\r\n");
+        self.inner.write_all(&chunk)
+    }
+
+    pub fn trailer(&mut self, name: &str, value: &str) {
+        self.trailers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.transforms.is_empty() {
+            let tail = self.run_transforms(&[], true)?;
+            self.write_payload(&tail)?;
+        }
+        if !self.chunked {
+            return self.inner.flush();
+        }
+        let mut end = b"0\r\n".to_vec();
+        for (name, value) in &self.trailers {
+            end.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        end.extend_from_slice(b"\r\n");
+        self.inner.write_all(&end)?;
+        self.inner.flush()
+    }
+}
+
+impl Write for ChunkedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.transforms.is_empty() {
+            self.write_payload(buf)?;
+        } else if !buf.is_empty() {
+            let payload = self.run_transforms(buf, false)?;
+            self.write_payload(&payload)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
 }
 
 impl Request {
-    pub fn parse(mut stream: impl Read) -> Result<Request, ParseError> {
-        let mut headers_buffer = vec![0; MAX_HEADER_SIZE];
+    pub fn parse(mut stream: impl Read, limits: &ParseLimits) -> Result<Request, ParseError> {
+        let mut request = Request::parse_head(&mut stream)?;
+        request.read_body(&mut stream, limits)?;
+        Ok(request)
+    }
+
+    // Reads the request line and headers only, leaving the body on the stream
+    pub fn parse_head(stream: impl Read) -> Result<Request, ParseError> {
+        Request::parse_head_limited(stream, DEFAULT_MAX_QUERY_PARAMS)
+    }
+
+    // As parse_head, refusing a query string with more than `max_query_params` entries
+    // before any of them is decoded
+    pub fn parse_head_limited(mut stream: impl Read, max_query_params: usize) -> Result<Request, ParseError> {
+        let mut headers_buffer = HeaderBuffer::checkout();
         let mut headers_pos = 0;
         let mut found_header_end = false;
         let mut retries = 0;
@@ -94,167 +505,740 @@ impl Request {
         }
 
         if !found_header_end {
+            if headers_pos == headers_buffer.len() && !headers_buffer.contains(&b'\n') {
+                return Err(ParseError::UriTooLong);
+            }
             return Err(ParseError::InvalidRequest);
         }
 
-        let headers_str = String::from_utf8_lossy(&headers_buffer[..headers_pos]);
+        // A non-UTF-8 byte anywhere in the head is refused rather than replaced, so header values
+        // (e.g. a Latin-1 filename) never reach handlers silently altered
+        let headers_str = std::str::from_utf8(&headers_buffer[..headers_pos])
+            .map_err(|_| ParseError::InvalidRequest)?;
         let mut lines = headers_str.lines();
 
         // Parse request line
         let request_line = lines.next().ok_or(ParseError::InvalidRequest)?;
         let mut parts = request_line.split_whitespace();
-        let method = Method::from(parts.next().ok_or(ParseError::InvalidRequest)?);
-        let path = parts.next().ok_or(ParseError::InvalidRequest)?.to_string();
+        let method = parts.next().ok_or(ParseError::InvalidRequest)?;
+        let target = parts.next().ok_or(ParseError::InvalidRequest)?;
+        let (path, raw_query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        let query = match raw_query.as_deref() {
+            Some(raw) => parse_query(raw, max_query_params)?,
+            None => QueryMap::default(),
+        };
+
+        // Only HTTP/1.x is spoken here; this also catches the HTTP/2 preface (PRI * HTTP/2.0).
+        // A request line without a version is treated as HTTP/1.0.
+        let version = parts.next().unwrap_or("HTTP/1.0").to_string();
+        if !version.starts_with("HTTP/1.") {
+            return Err(ParseError::UnsupportedVersion);
+        }
+        let method = Method::parse(method).ok_or(ParseError::UnsupportedMethod)?;
 
         // Parse headers
-        let mut headers = HashMap::new();
-        for line in lines {
+        let mut headers = HeaderMap::default();
+        for (count, line) in lines.enumerate() {
             if line.is_empty() {
                 break;
             }
-            if let Some((key, value)) = line.split_once(": ") {
-                headers.insert(key.to_string(), value.to_string());
+            // Count lines rather than map entries so duplicates and malformed lines are capped too
+            if count >= MAX_HEADER_COUNT {
+                return Err(ParseError::TooManyHeaders);
             }
-        }
-
-        let body = if let Some(length) = headers.get("Content-Length") {
-            let length: usize = length.parse().map_err(|_| ParseError::InvalidRequest)?;
-            if length > 1024 * 1024 * 10 { // 10MB limit
-                return Err(ParseError::ContentTooLarge);
+            // Obsolete line folding and whitespace before the colon are both refused, as RFC 7230
+            // allows, since proxies disagree on how to read them
+            if line.starts_with([' ', '\t']) {
+                return Err(ParseError::InvalidRequest);
             }
-            let mut body = vec![0; length];
-            let mut pos = 0;
-            let mut retries = 0;
-
-            while pos < length {
-                match stream.read(&mut body[pos..]) {
-                    Ok(0) => break, // EOF
-                    Ok(n) => {
-                        pos += n;
-                        retries = 0; // Reset retry counter on successful read
-                    }
-                    Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
-                        if retries < MAX_READ_RETRIES {
-                            retries += 1;
-                            thread::sleep(RETRY_DELAY);
-                            continue;
-                        }
-                        return Err(ParseError::IoError(e));
-                    }
-                    Err(e) => return Err(ParseError::IoError(e)),
+            if let Some((key, value)) = line.split_once(':') {
+                if key.is_empty() || key.ends_with([' ', '\t']) {
+                    return Err(ParseError::InvalidRequest);
                 }
-            }
-            body
-        } else if headers.get("Transfer-Encoding").map_or(false, |v| v.to_lowercase() == "chunked") {
-            let mut body = Vec::new();
-            let mut retries = 0;
-
-            loop {
-                let mut size_line = String::new();
-                let mut size_bytes = [0; 2];
-                
-                // Read chunk size with retry
-                'read_size: loop {
-                    match stream.read(&mut size_bytes[..1]) {
-                        Ok(0) => break,
-                        Ok(1) => {
-                            size_line.push(size_bytes[0] as char);
-                            if size_line.ends_with("\r\n") {
-                                break;
-                            }
-                            retries = 0;
+                let value = value.trim_matches([' ', '\t']);
+                match framing_header(key) {
+                    // A second Content-Length, even an identical one, leaves the body length
+                    // open to interpretation
+                    Some(name @ "Content-Length") => {
+                        if headers.contains_key(name) || value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                            return Err(ParseError::InvalidRequest);
                         }
-                        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
-                            if retries < MAX_READ_RETRIES {
-                                retries += 1;
-                                thread::sleep(RETRY_DELAY);
-                                continue 'read_size;
-                            }
-                            return Err(ParseError::IoError(e));
-                        }
-                        Err(e) => return Err(ParseError::IoError(e)),
-                        _ => continue,
+                        headers.insert(name.to_string(), value.to_string());
+                    }
+                    // Repeated Transfer-Encoding lines form one list of codings
+                    Some(name) => {
+                        headers.entry(name.to_string())
+                            .and_modify(|codings| {
+                                codings.push_str(", ");
+                                codings.push_str(value);
+                            })
+                            .or_insert_with(|| value.to_string());
+                    }
+                    None => {
+                        headers.insert(key.to_string(), value.to_string());
                     }
                 }
+            }
+        }
 
-                let size = usize::from_str_radix(size_line.trim_end(), 16)
-                    .map_err(|_| ParseError::InvalidRequest)?;
-                if size == 0 {
-                    break;
-                }
+        if let Some(encoding) = headers.get("Transfer-Encoding") {
+            // Both framings at once is a request smuggling vector, so refuse to pick one
+            if headers.contains_key("Content-Length") {
+                return Err(ParseError::InvalidRequest);
+            }
+            validate_transfer_codings(encoding)?;
+        }
 
-                let mut chunk = vec![0; size];
-                let mut pos = 0;
-                retries = 0;
+        Ok(Request {
+            method,
+            path,
+            query,
+            raw_query,
+            version,
+            headers,
+            body: RequestBody::default(),
+            remote_addr: None,
+            params: HashMap::new(),
+            extensions: Extensions::default(),
+            deferred_body: RefCell::new(None),
+            connection: None,
+        })
+    }
 
-                // Read chunk data with retry
-                while pos < size {
-                    match stream.read(&mut chunk[pos..]) {
-                        Ok(0) => break,
-                        Ok(n) => {
-                            pos += n;
-                            retries = 0;
-                        }
-                        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
-                            if retries < MAX_READ_RETRIES {
-                                retries += 1;
-                                thread::sleep(RETRY_DELAY);
-                                continue;
-                            }
-                            return Err(ParseError::IoError(e));
-                        }
-                        Err(e) => return Err(ParseError::IoError(e)),
+    // The query string as sent, without the `?`
+    pub fn raw_query(&self) -> Option<&str> {
+        self.raw_query.as_deref()
+    }
+
+    // Every value of a query parameter, in order, for keys like `?tag=a&tag=b`
+    pub fn query_all(&self, key: &str) -> Vec<String> {
+        self.raw_query.as_deref().map_or_else(Vec::new, |raw| {
+            query_pairs(raw)
+                .filter_map(|(k, v)| Some((percent_decode(k, true).ok()?, percent_decode(v, true).ok()?)))
+                .filter(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .collect()
+        })
+    }
+
+    // The path and query string as they would appear in a request line
+    pub fn target(&self) -> String {
+        match &self.raw_query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        }
+    }
+
+    // For middleware rewriting the target before routing. A new path without its own
+    // query string keeps the original one.
+    pub fn set_path(&mut self, path: &str) {
+        match path.split_once('?') {
+            Some((path, query)) => {
+                self.path = path.to_string();
+                self.query = parse_query(query, DEFAULT_MAX_QUERY_PARAMS).unwrap_or_default();
+                self.raw_query = Some(query.to_string());
+            }
+            None => self.path = path.to_string(),
+        }
+    }
+
+    // HTTP/1.1 connections persist unless the client sends `Connection: close`;
+    // HTTP/1.0 clients have to ask with `Connection: keep-alive`
+    pub fn wants_keep_alive(&self) -> bool {
+        let tokens: Vec<String> = self.headers.iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+            .flat_map(|(_, value)| value.split(',').map(|t| t.trim().to_ascii_lowercase()))
+            .collect();
+        if tokens.iter().any(|t| t == "close") {
+            return false;
+        }
+        self.version != "HTTP/1.0" || tokens.iter().any(|t| t == "keep-alive")
+    }
+
+    // True while a deferred body is still unread on the connection
+    pub fn has_unread_body(&self) -> bool {
+        self.deferred_body.borrow().is_some()
+    }
+
+    // Header names keep the client's spelling, apart from the framing headers, so lookups
+    // by a fixed name go through here
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn content_length(&self) -> Option<usize> {
+        self.headers.get("Content-Length").and_then(|v| v.parse().ok())
+    }
+
+    pub fn expects_continue(&self) -> bool {
+        self.header("Expect").is_some_and(|v| v.trim().eq_ignore_ascii_case("100-continue"))
+    }
+
+    pub fn read_body(&mut self, stream: impl Read, limits: &ParseLimits) -> Result<(), ParseError> {
+        self.body = read_body_from(&self.headers, stream, limits)?;
+        Ok(())
+    }
+
+    // Leaves the body on the stream until a handler calls `load_body`
+    pub fn defer_body(&mut self, stream: TcpStream, limits: ParseLimits) {
+        self.deferred_body = RefCell::new(Some((stream, limits)));
+    }
+
+    // Returns the body, reading it from the connection first if it was deferred.
+    // A deferred body can only be consumed once; later calls return `self.body`.
+    // Spooled bodies are read back into memory; use `body.reader()` to stream them instead.
+    pub fn load_body(&self) -> Result<Vec<u8>, ParseError> {
+        match self.deferred_body.borrow_mut().take() {
+            Some((stream, limits)) => Ok(read_body_from(&self.headers, stream, &limits)?.to_vec()?),
+            None => Ok(self.body.to_vec()?),
+        }
+    }
+
+    // Deserializes the body with the parser registered for its Content-Type (JSON and
+    // urlencoded forms by default, more through Server::with_body_parser)
+    pub fn parse_body<T: DeserializeOwned>(&self) -> Result<T, BodyError> {
+        let body = self.load_body()?;
+        let content_type = self.headers.get("Content-Type").map(String::as_str);
+        let value = match self.extensions.get::<Arc<BodyParsers>>() {
+            Some(parsers) => parsers.parse(content_type, &body)?,
+            None => BodyParsers::default().parse(content_type, &body)?,
+        };
+        serde_json::from_value(value).map_err(|e| BodyError::Malformed(e.to_string()))
+    }
+
+    // Gives handlers a way to write 1xx responses ahead of the final one and to check whether
+    // the client is still there
+    pub fn set_connection(&mut self, stream: TcpStream) {
+        self.connection = Some(stream);
+    }
+
+    // Best-effort check, for long-running handlers, that the client has not gone away. A peek
+    // that sees EOF or a reset means disconnected. It cannot tell a client that only half-closed
+    // its side after sending the request from one that left, and a client that vanished without
+    // a FIN or RST (e.g. a dropped network) still looks connected until a write fails.
+    //
+    // The socket is briefly switched to non-blocking for the peek, and that mode belongs to the
+    // socket itself, so it is seen through every handle to it, cloned or not. This is only safe
+    // while nothing else does I/O on the connection. That holds for handlers: the server does
+    // not touch the socket until the handler returns, and Request is not Sync, so no other
+    // thread can be reading or writing through it at the same time.
+    pub fn is_connected(&self) -> bool {
+        let Some(stream) = self.connection.as_ref() else {
+            return true;
+        };
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+        let connected = match stream.peek(&mut [0; 1]) {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(e) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted),
+        };
+        // The socket is shared with the connection handler, which expects blocking reads
+        let _ = stream.set_nonblocking(false);
+        connected
+    }
+
+    // Sends a 1xx response (e.g. 103 Early Hints) immediately, before the handler returns.
+    // 101 is excluded since switching protocols would end the HTTP/1.1 exchange.
+    pub fn send_informational(&self, response: &Response) -> io::Result<()> {
+        if !(100..200).contains(&response.status_code) || response.status_code == 101 {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "not an informational response"));
+        }
+        let mut stream = self.connection.as_ref()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "no connection for interim responses"))?;
+        stream.write_all(&response.head_bytes())?;
+        stream.flush()
+    }
+}
+
+// Header names are otherwise kept as sent, but the framing headers are stored under one
+// spelling so that every length check sees them whatever case the client used
+fn framing_header(name: &str) -> Option<&'static str> {
+    ["Content-Length", "Transfer-Encoding"].into_iter().find(|h| h.eq_ignore_ascii_case(name))
+}
+
+// A request Transfer-Encoding is a list of codings applied in order. The body can only be
+// framed if chunked is applied exactly once and last. Other codings would have to be decoded
+// from the dechunked body, which this server has no decoders for, so they are refused.
+fn validate_transfer_codings(value: &str) -> Result<(), ParseError> {
+    let codings: Vec<String> = value
+        .split(',')
+        .map(|c| c.trim().to_ascii_lowercase())
+        .filter(|c| !c.is_empty())
+        .collect();
+
+    match codings.iter().position(|c| c == "chunked") {
+        Some(pos) if pos == codings.len() - 1 => {}
+        _ => return Err(ParseError::InvalidRequest),
+    }
+    if codings[..codings.len() - 1].iter().any(|c| c != "identity") {
+        return Err(ParseError::UnsupportedTransferCoding);
+    }
+    Ok(())
+}
+
+pub fn read_body_from(
+    headers: &HeaderMap,
+    mut stream: impl Read,
+    limits: &ParseLimits,
+) -> Result<RequestBody, ParseError> {
+    let body = if let Some(length) = headers.get("Content-Length") {
+        let length: usize = length.parse().map_err(|_| ParseError::InvalidRequest)?;
+        if length > limits.max_body_size {
+            return Err(ParseError::ContentTooLarge);
+        }
+        // Grow with the data actually received rather than trusting the declared length
+        let mut body = BodyBuffer::new(length.min(BODY_READ_CHUNK_SIZE), limits.spool_threshold);
+        let mut buffer = [0; BODY_READ_CHUNK_SIZE];
+        let mut retries = 0;
+
+        while body.len() < length {
+            let want = (length - body.len()).min(buffer.len());
+            match stream.read(&mut buffer[..want]) {
+                Ok(0) => break, // EOF
+                Ok(n) => {
+                    body.write_all(&buffer[..n])?;
+                    retries = 0; // Reset retry counter on successful read
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    if retries < MAX_READ_RETRIES {
+                        retries += 1;
+                        thread::sleep(RETRY_DELAY);
+                        continue;
                     }
+                    return Err(ParseError::IoError(e));
                 }
+                Err(e) => return Err(ParseError::IoError(e)),
+            }
+        }
+        body
+    } else if headers.contains_key("Transfer-Encoding") {
+        // parse_head has already checked that the codings end in chunked
+        let mut body = BodyBuffer::new(0, limits.spool_threshold);
+        copy_chunked_body(&mut stream, &mut body, limits)?;
+        body
+    } else {
+        BodyBuffer::new(0, None)
+    };
+
+    Ok(body.finish()?)
+}
+
+// Decodes a chunked body from `stream` into `out` one chunk at a time, so the body is never
+// held whole; the chunk and total sizes are checked against `limits` as they are announced
+pub fn copy_chunked_body(mut stream: impl Read, out: &mut impl Write, limits: &ParseLimits) -> Result<(), ParseError> {
+    let mut buffer = [0; BODY_READ_CHUNK_SIZE];
+    let mut total: usize = 0;
+
+    loop {
+        let size_line = read_chunk_line(&mut stream)?;
+        // Chunk extensions (`;name=value`) carry nothing we use
+        let size_hex = size_line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_hex, 16).map_err(|e| match e.kind() {
+            IntErrorKind::PosOverflow => ParseError::ContentTooLarge,
+            _ => ParseError::InvalidRequest,
+        })?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_chunk_size || total.saturating_add(size) > limits.max_body_size {
+            return Err(ParseError::ContentTooLarge);
+        }
+        total += size;
+
+        // Copy through a fixed buffer so the declared size never decides an allocation
+        let mut remaining = size;
+        while remaining > 0 {
+            let n = remaining.min(buffer.len());
+            read_exact_retrying(&mut stream, &mut buffer[..n])?;
+            out.write_all(&buffer[..n])?;
+            remaining -= n;
+        }
+
+        let mut crlf = [0; 2];
+        read_exact_retrying(&mut stream, &mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(ParseError::InvalidRequest);
+        }
+    }
+
+    // The last chunk is followed by optional trailer fields and an empty line; reading them
+    // leaves nothing unread that would make closing the socket reset the connection
+    let mut trailers = 0;
+    while !read_chunk_line(&mut stream)?.is_empty() {
+        trailers += 1;
+        if trailers > MAX_HEADER_COUNT {
+            return Err(ParseError::TooManyHeaders);
+        }
+    }
+    Ok(())
+}
+
+fn query_pairs(raw: &str) -> impl Iterator<Item = (&str, &str)> {
+    raw.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| pair.split_once('=').unwrap_or((pair, "")))
+}
+
+// `a=1&b=x+y%21&debug` -> {a: "1", b: "x y!", debug: ""}; a bad escape makes the request invalid
+fn parse_query(raw: &str, max_params: usize) -> Result<QueryMap, ParseError> {
+    if query_pairs(raw).nth(max_params).is_some() {
+        return Err(ParseError::TooManyQueryParams);
+    }
+    let mut query = QueryMap::default();
+    for (key, value) in query_pairs(raw) {
+        let key = percent_decode(key, true).map_err(|_| ParseError::InvalidRequest)?;
+        let value = percent_decode(value, true).map_err(|_| ParseError::InvalidRequest)?;
+        query.insert(key, value);
+    }
+    Ok(query)
+}
 
-                body.extend(chunk);
+// One CRLF-terminated line of chunked framing, without the CRLF
+fn read_chunk_line(stream: &mut impl Read) -> Result<String, ParseError> {
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= MAX_CHUNK_LINE_SIZE {
+            return Err(ParseError::InvalidRequest);
+        }
+        read_exact_retrying(stream, &mut byte)?;
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| ParseError::InvalidRequest)
+}
 
-                // Read trailing CRLF with retry
+// EOF before the buffer is full means the body was cut short
+fn read_exact_retrying(stream: &mut impl Read, buf: &mut [u8]) -> Result<(), ParseError> {
+    let mut pos = 0;
+    let mut retries = 0;
+    while pos < buf.len() {
+        match stream.read(&mut buf[pos..]) {
+            Ok(0) => return Err(ParseError::InvalidRequest),
+            Ok(n) => {
+                pos += n;
                 retries = 0;
-                'read_crlf: loop {
-                    match stream.read(&mut size_bytes) {
-                        Ok(2) => break,
-                        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
-                            if retries < MAX_READ_RETRIES {
-                                retries += 1;
-                                thread::sleep(RETRY_DELAY);
-                                continue 'read_crlf;
-                            }
-                            return Err(ParseError::IoError(e));
-                        }
-                        Err(e) => return Err(ParseError::IoError(e)),
-                        _ => continue,
-                    }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                if retries < MAX_READ_RETRIES {
+                    retries += 1;
+                    thread::sleep(RETRY_DELAY);
+                    continue;
                 }
+                return Err(ParseError::IoError(e));
             }
-            body
-        } else {
-            Vec::new()
-        };
+            Err(e) => return Err(ParseError::IoError(e)),
+        }
+    }
+    Ok(())
+}
 
-        Ok(Request {
-            method,
-            path,
-            headers,
-            body,
-        })
+// `%XX` escapes to bytes; `+` means a space only in form and query encoding, not in paths
+pub fn percent_decode(input: &str, plus_as_space: bool) -> Result<String, String> {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus_as_space => out.push(b' '),
+            b'%' => {
+                let hex = bytes.get(i + 1..i + 3)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("invalid percent escape in '{}'", input))?;
+                out.push(hex);
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8(out).map_err(|_| format!("'{}' does not decode to UTF-8", input))
+}
+
+// Fluent alternative to building a Response and then editing its headers, e.g.
+// `Response::builder().status(201).header("Location", "/x").body(vec![]).build()`
+pub struct ResponseBuilder {
+    status_code: u16,
+    status_text: Option<String>,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status_code: u16) -> Self {
+        self.status_code = status_code;
+        self
+    }
+
+    // Overrides the standard reason phrase for the status
+    pub fn status_text(mut self, status_text: &str) -> Self {
+        self.status_text = Some(status_text.to_string());
+        self
+    }
+
+    // Replaces a default or earlier header of the same name, whatever its casing
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    // Starts from the same defaults as Response::new; Content-Type is only sent if set
+    pub fn build(self) -> Response {
+        let status_text = self.status_text.unwrap_or_else(|| reason_phrase(self.status_code).to_string());
+        let mut response = Response::new(self.status_code, &status_text, "", self.body);
+        response.headers.remove("Content-Type");
+        for (name, value) in self.headers {
+            response.headers.retain(|existing, _| !existing.eq_ignore_ascii_case(&name));
+            response.headers.insert(name, value);
+        }
+        response
     }
 }
 
 impl Response {
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder { status_code: 200, status_text: None, headers: Vec::new(), body: Vec::new() }
+    }
+
     pub fn new(status_code: u16, status_text: &str, content_type: &str, body: Vec<u8>) -> Response {
         let mut headers = HashMap::new();
         headers.insert("Content-Type".to_string(), content_type.to_string());
         headers.insert("Content-Length".to_string(), body.len().to_string());
         headers.insert("Connection".to_string(), "close".to_string());
         headers.insert("Server".to_string(), "Rust-HTTP-Server/1.0".to_string());
+        headers.insert("Date".to_string(), Utc::now().format(HTTP_DATE_FORMAT).to_string());
         
         Response {
             status_code,
             status_text: status_text.to_string(),
             headers,
+            appended_headers: Vec::new(),
             body,
+            stream: None,
+            transforms: Vec::new(),
+        }
+    }
+
+    // Whether the connection has to close after this response even when the client asked to
+    // keep it open: on any 5xx, since the server may have left the stream in an unknown state,
+    // and when the body has no determinate length (neither Content-Length nor chunked), since
+    // the client can then only find its end by EOF. Requests that fail to parse always close
+    // as well; that is decided by the caller, which never gets a Request to pair with this.
+    pub fn forces_close(&self) -> bool {
+        if self.status_code >= 500 {
+            return true;
+        }
+        let bodiless = (100..200).contains(&self.status_code) || self.status_code == 204 || self.status_code == 304;
+        let header = |name: &str| self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v);
+        let chunked = header("Transfer-Encoding").is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        !bodiless && !chunked && header("Content-Length").and_then(|v| v.parse::<u64>().ok()).is_none()
+    }
+
+    pub fn stream<F>(status_code: u16, status_text: &str, content_type: &str, producer: F) -> Response
+    where
+        F: FnOnce(&mut ChunkedWriter<'_>) -> io::Result<()> + Send + 'static,
+    {
+        let mut response = Response::new(status_code, status_text, content_type, Vec::new());
+        response.headers.remove("Content-Length");
+        response.headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        response.stream = Some(Box::new(producer));
+        response
+    }
+
+    // Serializes items into a chunked JSON array one at a time, so a large collection is
+    // never held in memory as a single string
+    pub fn json_stream<I>(items: I) -> Response
+    where
+        I: IntoIterator + Send + 'static,
+        I::Item: Serialize,
+    {
+        Response::stream(200, "OK", "application/json", move |writer| {
+            // Batch small items into reasonably sized chunks
+            let mut out = io::BufWriter::with_capacity(BODY_READ_CHUNK_SIZE, writer);
+            out.write_all(b"[")?;
+            for (i, item) in items.into_iter().enumerate() {
+                if i > 0 {
+                    out.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut out, &item)?;
+            }
+            out.write_all(b"]")?;
+            out.flush()
+        })
+    }
+
+    // A bare status line plus headers; 1xx responses carry no body or framing headers
+    pub fn informational(status_code: u16, status_text: &str) -> Response {
+        Response {
+            status_code,
+            status_text: status_text.to_string(),
+            headers: HashMap::new(),
+            appended_headers: Vec::new(),
+            body: Vec::new(),
+            stream: None,
+            transforms: Vec::new(),
+        }
+    }
+
+    // Each link is a full Link value, e.g. `</style.css>; rel=preload; as=style`
+    pub fn early_hints(links: &[&str]) -> Response {
+        let mut response = Response::informational(103, "Early Hints");
+        response.headers.insert("Link".to_string(), links.join(", "));
+        response
+    }
+
+    // Appends to any existing Vary instead of replacing it, skipping names already listed
+    pub fn add_vary(&mut self, field: &str) {
+        let existing = self.headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Vary"))
+            .map(|(name, value)| (name.clone(), value.clone()));
+
+        match existing {
+            Some((name, value)) => {
+                let listed = value.split(',').map(str::trim)
+                    .any(|v| v == "*" || v.eq_ignore_ascii_case(field));
+                if !listed {
+                    self.headers.insert(name, format!("{}, {}", value, field));
+                }
+            }
+            None => {
+                self.headers.insert("Vary".to_string(), field.to_string());
+            }
+        }
+    }
+
+    // Adds another line for a header instead of replacing it; the first value of a name still
+    // goes into `headers`, so lookups and replacements there keep working
+    pub fn append_header(&mut self, name: &str, value: &str) {
+        if self.headers.keys().any(|existing| existing.eq_ignore_ascii_case(name)) {
+            self.appended_headers.push((name.to_string(), value.to_string()));
+        } else {
+            self.headers.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    // Every header line, including appended ones
+    pub fn header_lines(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.headers.iter().chain(self.appended_headers.iter().map(|(name, value)| (name, value)))
+    }
+
+    pub fn with_retry_after(mut self, retry_after: RetryAfter) -> Response {
+        self.headers.insert("Retry-After".to_string(), retry_after.to_header_value());
+        self
+    }
+
+    // Adds a transform applied lazily while the body is written, after any pushed earlier, so
+    // body-rewriting middleware can chain without each buffering the whole body. The length
+    // is unknown until then, so the body is sent chunked. Bodiless statuses are left alone.
+    pub fn push_transform<T: BodyTransform + 'static>(&mut self, transform: T) {
+        let bodiless = (100..200).contains(&self.status_code) || self.status_code == 204 || self.status_code == 304;
+        if bodiless {
+            return;
+        }
+        if self.stream.is_none() {
+            let body = std::mem::take(&mut self.body);
+            self.stream = Some(Box::new(move |writer| writer.write_all(&body)));
+        }
+        self.headers.retain(|name, _| !name.eq_ignore_ascii_case("Content-Length"));
+        self.headers.insert("Transfer-Encoding".to_string(), "chunked".to_string());
+        self.transforms.push(Box::new(transform));
+    }
+
+    pub fn take_transforms(&mut self) -> Vec<Box<dyn BodyTransform>> {
+        std::mem::take(&mut self.transforms)
+    }
+
+    // Buffered responses only; a streamed body can be consumed once
+    pub fn try_clone(&self) -> Option<Response> {
+        if self.stream.is_some() {
+            return None;
+        }
+        Some(Response {
+            status_code: self.status_code,
+            status_text: self.status_text.clone(),
+            headers: self.headers.clone(),
+            appended_headers: self.appended_headers.clone(),
+            body: self.body.clone(),
+            stream: None,
+            transforms: Vec::new(),
+        })
+    }
+
+    // Serves the part of a buffered 200 body that a `Range: bytes=...` header asks for, for
+    // handlers whose output is not a file. Only single ranges are honoured: a multi-range or
+    // malformed header gets the full body, and a range starting past the end is a 416.
+    pub fn apply_range(mut self, range_header: Option<&str>) -> Response {
+        if self.status_code != 200 || self.stream.is_some() {
+            return self;
+        }
+        self.headers.insert("Accept-Ranges".to_string(), "bytes".to_string());
+        let spec = range_header
+            .and_then(|value| value.trim().split_once('='))
+            .filter(|(unit, _)| unit.trim().eq_ignore_ascii_case("bytes"))
+            .map(|(_, spec)| spec.trim());
+        let Some(spec) = spec else {
+            return self;
+        };
+
+        let len = self.body.len();
+        match parse_byte_range(spec, len) {
+            ByteRange::Satisfiable(start, end) => {
+                self.body = self.body[start..=end].to_vec();
+                self.status_code = 206;
+                self.status_text = "Partial Content".to_string();
+                self.headers.insert("Content-Range".to_string(), format!("bytes {}-{}/{}", start, end, len));
+            }
+            ByteRange::Unsatisfiable => {
+                self.body.clear();
+                self.status_code = 416;
+                self.status_text = "Range Not Satisfiable".to_string();
+                self.headers.insert("Content-Range".to_string(), format!("bytes */{}", len));
+            }
+            ByteRange::Ignored => return self,
+        }
+        self.headers.insert("Content-Length".to_string(), self.body.len().to_string());
+        self
+    }
+
+    // Streams any reader as the body: with a known length it is sent as-is under
+    // Content-Length, otherwise it is chunked
+    pub fn from_reader<R>(reader: R, content_length: Option<usize>) -> Response
+    where
+        R: Read + Send + 'static,
+    {
+        let limit = content_length.map_or(u64::MAX, |length| length as u64);
+        let mut response = Response::stream(200, "OK", "application/octet-stream", move |writer| {
+            let copied = io::copy(&mut reader.take(limit), writer)?;
+            if content_length.is_some() && copied < limit {
+                // The client was promised more bytes than exist; fail rather than let it hang
+                return Err(io::Error::new(ErrorKind::UnexpectedEof, "reader ended before Content-Length"));
+            }
+            Ok(())
+        });
+        if let Some(length) = content_length {
+            response.headers.remove("Transfer-Encoding");
+            response.headers.insert("Content-Length".to_string(), length.to_string());
         }
+        response
+    }
+
+    // Announces trailer fields that a streaming body will send after its last chunk
+    pub fn declare_trailers(&mut self, names: &[&str]) {
+        self.headers.insert("Trailer".to_string(), names.join(", "));
     }
     
     pub fn ok(content_type: &str, body: Vec<u8>) -> Response {
@@ -273,7 +1257,6 @@ impl Response {
             </html>".to_vec())
     }
     
-    #[allow(dead_code)]
     pub fn internal_server_error() -> Response {
         Response::new(500, "Internal Server Error", "text/html",
             b"<!DOCTYPE html>\
@@ -300,6 +1283,13 @@ impl Response {
         response
     }
 
+    pub fn no_content() -> Response {
+        let mut response = Response::new(204, "No Content", "", Vec::new());
+        response.headers.remove("Content-Type");
+        response.headers.remove("Content-Length");
+        response
+    }
+
     pub fn bad_request(message: &str) -> Response {
         Response::new(400, "Bad Request", "text/html",
             format!("<!DOCTYPE html>\
@@ -309,26 +1299,518 @@ impl Response {
                 <h1>400 Bad Request</h1>\
                 <p>{}</p>\
             </body>\
-            </html>", message).into_bytes())
+            </html>", html_escape(message)).into_bytes())
     }
     
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut response = Vec::new();
+    pub fn too_many_requests() -> Response {
+        Response::new(429, "Too Many Requests", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>429 Too Many Requests</title></head>\
+            <body>\
+                <h1>429 Too Many Requests</h1>\
+                <p>Too many concurrent requests from your address. Please retry later.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn service_unavailable() -> Response {
+        Response::new(503, "Service Unavailable", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>503 Service Unavailable</title></head>\
+            <body>\
+                <h1>503 Service Unavailable</h1>\
+                <p>The server is too busy to handle this request. Please retry later.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn request_header_fields_too_large() -> Response {
+        Response::new(431, "Request Header Fields Too Large", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>431 Request Header Fields Too Large</title></head>\
+            <body>\
+                <h1>431 Request Header Fields Too Large</h1>\
+                <p>The request carries more header fields than this server accepts.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn unsupported_media_type() -> Response {
+        Response::new(415, "Unsupported Media Type", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>415 Unsupported Media Type</title></head>\
+            <body>\
+                <h1>415 Unsupported Media Type</h1>\
+                <p>The request body's Content-Type is not accepted by this resource.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn uri_too_long() -> Response {
+        Response::new(414, "URI Too Long", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>414 URI Too Long</title></head>\
+            <body>\
+                <h1>414 URI Too Long</h1>\
+                <p>The request URI or its query string is longer than this server accepts.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn not_implemented() -> Response {
+        Response::new(501, "Not Implemented", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>501 Not Implemented</title></head>\
+            <body>\
+                <h1>501 Not Implemented</h1>\
+                <p>The request uses a feature this server does not support.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn bad_gateway() -> Response {
+        Response::new(502, "Bad Gateway", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>502 Bad Gateway</title></head>\
+            <body>\
+                <h1>502 Bad Gateway</h1>\
+                <p>The server received an invalid response from an upstream server.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn gateway_timeout() -> Response {
+        Response::new(504, "Gateway Timeout", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>504 Gateway Timeout</title></head>\
+            <body>\
+                <h1>504 Gateway Timeout</h1>\
+                <p>An upstream server did not respond in time.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn http_version_not_supported() -> Response {
+        Response::new(505, "HTTP Version Not Supported", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>505 HTTP Version Not Supported</title></head>\
+            <body>\
+                <h1>505 HTTP Version Not Supported</h1>\
+                <p>This server only supports HTTP/1.x.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn unauthorized() -> Response {
+        let mut response = Response::new(401, "Unauthorized", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>401 Unauthorized</title></head>\
+            <body>\
+                <h1>401 Unauthorized</h1>\
+                <p>Valid credentials are required to access this resource.</p>\
+            </body>\
+            </html>".to_vec());
+        response.headers.insert("WWW-Authenticate".to_string(), "Bearer".to_string());
+        response
+    }
+
+    pub fn forbidden() -> Response {
+        Response::new(403, "Forbidden", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>403 Forbidden</title></head>\
+            <body>\
+                <h1>403 Forbidden</h1>\
+                <p>You do not have permission to access this resource.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+
+    pub fn length_required() -> Response {
+        Response::new(411, "Length Required", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>411 Length Required</title></head>\
+            <body>\
+                <h1>411 Length Required</h1>\
+                <p>A Content-Length header is required for this request.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn payload_too_large() -> Response {
+        Response::new(413, "Payload Too Large", "text/html",
+            b"<!DOCTYPE html>\
+            <html>\
+            <head><title>413 Payload Too Large</title></head>\
+            <body>\
+                <h1>413 Payload Too Large</h1>\
+                <p>The request body exceeds the size this server accepts.</p>\
+            </body>\
+            </html>".to_vec())
+    }
+    
+    pub fn head_bytes(&self) -> Vec<u8> {
+        let mut head = Vec::new();
         
-        response.extend_from_slice(
+        head.extend_from_slice(
             format!("HTTP/1.1 {} {}\r\n", self.status_code, self.status_text).as_bytes()
         );
         
         // Headers
-        for (key, value) in &self.headers {
-            response.extend_from_slice(
+        for (key, value) in self.header_lines() {
+            head.extend_from_slice(
                 format!("{}: {}\r\n", key, value).as_bytes()
             );
         }
         
-        response.extend_from_slice(b"\r\n");
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut response = self.head_bytes();
         response.extend_from_slice(&self.body);
-        
         response
     }
-} 
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> ParseLimits {
+        ParseLimits { max_body_size: 1024, max_chunk_size: 1024, spool_threshold: None }
+    }
+
+    fn parse(raw: &str) -> Result<Request, ParseError> {
+        Request::parse(raw.as_bytes(), &limits())
+    }
+
+    #[test]
+    fn rejects_both_framings_whatever_the_case() {
+        let raw = "POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidRequest)));
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\nTRANSFER-ENCODING: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidRequest)));
+        let raw = "POST / HTTP/1.1\r\ncontent-length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidRequest)));
+    }
+
+    #[test]
+    fn rejects_duplicate_content_length() {
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 3\r\n\r\nhello";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidRequest)));
+        let raw = "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidRequest)));
+    }
+
+    #[test]
+    fn rejects_content_length_that_is_not_digits() {
+        for value in ["+5", "-1", "5, 5", "0x5", ""] {
+            let raw = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\nhello", value);
+            assert!(matches!(parse(&raw), Err(ParseError::InvalidRequest)), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn frames_bodies_by_lowercase_headers() {
+        let request = parse("POST / HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello").unwrap();
+        assert_eq!(request.content_length(), Some(5));
+        assert_eq!(request.body.to_vec().unwrap(), b"hello");
+
+        let request = parse("POST / HTTP/1.1\r\ntransfer-encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n").unwrap();
+        assert_eq!(request.body.to_vec().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn joins_repeated_transfer_encoding_lines() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\ntransfer-encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::UnsupportedTransferCoding)));
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n";
+        assert!(matches!(parse(raw), Err(ParseError::InvalidRequest)));
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: identity\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\n";
+        assert_eq!(parse(raw).unwrap().body.to_vec().unwrap(), b"hi");
+    }
+
+    #[test]
+    fn is_connected_sees_the_client_leave() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server_side, _) = listener.accept().unwrap();
+
+        let mut request = parse("GET / HTTP/1.1\r\n\r\n").unwrap();
+        assert!(request.is_connected());
+        request.set_connection(server_side.try_clone().unwrap());
+        assert!(request.is_connected());
+
+        drop(client);
+        thread::sleep(Duration::from_millis(50));
+        assert!(!request.is_connected());
+    }
+
+    #[test]
+    fn a_request_line_that_fills_the_head_is_too_long() {
+        let raw = format!("GET /?q={} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEADER_SIZE));
+        assert!(matches!(parse(&raw), Err(ParseError::UriTooLong)));
+        // Long headers after a short request line are a different problem
+        let raw = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(MAX_HEADER_SIZE));
+        assert!(matches!(parse(&raw), Err(ParseError::InvalidRequest)));
+    }
+
+    #[test]
+    fn retry_after_parses_both_forms() {
+        assert_eq!(RetryAfter::parse("120"), Some(RetryAfter::Seconds(120)));
+        assert_eq!(RetryAfter::parse(" 0 "), Some(RetryAfter::Seconds(0)));
+        for invalid in ["", "-1", "1.5", "soon", "99999999999999999999999"] {
+            assert_eq!(RetryAfter::parse(invalid), None, "{:?}", invalid);
+        }
+
+        let date = chrono::TimeZone::with_ymd_and_hms(&Utc, 2015, 10, 21, 7, 28, 0).unwrap();
+        assert_eq!(RetryAfter::parse("Wed, 21 Oct 2015 07:28:00 GMT"), Some(RetryAfter::Date(date)));
+        assert_eq!(RetryAfter::Date(date).delay(), Duration::ZERO);
+        assert_eq!(RetryAfter::after(Duration::from_millis(1200)), RetryAfter::Seconds(2));
+    }
+
+    #[test]
+    fn retry_after_round_trips_through_the_header() {
+        let date = chrono::TimeZone::with_ymd_and_hms(&Utc, 2030, 1, 2, 3, 4, 5).unwrap();
+        for retry_after in [RetryAfter::Seconds(30), RetryAfter::Date(date)] {
+            let response = Response::service_unavailable().with_retry_after(retry_after);
+            let value = response.headers.get("Retry-After").unwrap();
+            assert_eq!(RetryAfter::parse(value), Some(retry_after), "{}", value);
+        }
+        assert_eq!(RetryAfter::Date(date).to_header_value(), "Wed, 02 Jan 2030 03:04:05 GMT");
+    }
+
+    #[test]
+    fn splits_and_decodes_the_query() {
+        let request = parse("GET /search HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!((request.path.as_str(), request.raw_query()), ("/search", None));
+        assert!(request.query.is_empty());
+        // A bare `?` is an empty query, not a missing one
+        let request = parse("GET /search? HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!((request.path.as_str(), request.raw_query()), ("/search", Some("")));
+        assert!(request.query.is_empty());
+
+        let request = parse("GET /search?q=hello%20big+world&debug&name%21=%E2%9C%93 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(request.path, "/search");
+        assert_eq!(request.query.get("q").map(String::as_str), Some("hello big world"));
+        assert_eq!(request.query.get("debug").map(String::as_str), Some(""));
+        assert_eq!(request.query.get("name!").map(String::as_str), Some("✓"));
+        assert_eq!(request.query.len(), 3);
+    }
+
+    #[test]
+    fn repeated_query_keys_keep_every_value() {
+        let request = parse("GET /posts?tag=a&tag=b+c&page=2 HTTP/1.1\r\n\r\n").unwrap();
+        // The map keeps the last value
+        assert_eq!(request.query.get("tag").map(String::as_str), Some("b c"));
+        assert_eq!(request.query_all("tag"), ["a", "b c"]);
+        assert!(request.query_all("missing").is_empty());
+    }
+
+    #[test]
+    fn bad_query_escapes_are_rejected() {
+        assert!(matches!(parse("GET /?q=%zz HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidRequest)));
+        assert!(matches!(parse("GET /?q=%2 HTTP/1.1\r\n\r\n"), Err(ParseError::InvalidRequest)));
+    }
+
+    #[test]
+    fn unknown_methods_are_not_taken_for_get() {
+        assert_eq!(Method::parse("get"), Some(Method::GET));
+        assert_eq!(Method::parse("FROBNICATE"), None);
+        assert!(matches!(parse("FROBNICATE / HTTP/1.1\r\n\r\n"), Err(ParseError::UnsupportedMethod)));
+        assert!(matches!(parse("CONNECT example.com:443 HTTP/1.1\r\n\r\n"), Err(ParseError::UnsupportedMethod)));
+    }
+
+    #[test]
+    fn header_whitespace_after_the_colon_is_optional() {
+        let request = parse("GET / HTTP/1.1\r\nHost:example.com\r\nX-Padded:   value \t\r\nX-Tab:\tvalue\r\nX-Empty:\r\n\r\n").unwrap();
+        assert_eq!(request.headers.get("Host").map(String::as_str), Some("example.com"));
+        assert_eq!(request.headers.get("X-Padded").map(String::as_str), Some("value"));
+        assert_eq!(request.headers.get("X-Tab").map(String::as_str), Some("value"));
+        assert_eq!(request.headers.get("X-Empty").map(String::as_str), Some(""));
+        // Colons after the first belong to the value
+        let request = parse("GET / HTTP/1.1\r\nHost:localhost:8080\r\n\r\n").unwrap();
+        assert_eq!(request.headers.get("Host").map(String::as_str), Some("localhost:8080"));
+    }
+
+    #[test]
+    fn folded_headers_and_space_before_the_colon_are_rejected() {
+        for raw in [
+            "GET / HTTP/1.1\r\nX-Folded: first\r\n second\r\n\r\n",
+            "GET / HTTP/1.1\r\nX-Folded: first\r\n\tsecond\r\n\r\n",
+            "GET / HTTP/1.1\r\nHost : example.com\r\n\r\n",
+            "GET / HTTP/1.1\r\n: no name\r\n\r\n",
+        ] {
+            assert!(matches!(parse(raw), Err(ParseError::InvalidRequest)), "{:?}", raw);
+        }
+    }
+
+    fn ranged(range: Option<&str>) -> Response {
+        Response::ok("text/plain", b"0123456789".to_vec()).apply_range(range)
+    }
+
+    fn content_range(response: &Response) -> Option<&str> {
+        response.headers.get("Content-Range").map(String::as_str)
+    }
+
+    #[test]
+    fn applies_valid_open_ended_and_suffix_ranges() {
+        for (range, body, content) in [
+            ("bytes=2-5", &b"2345"[..], "bytes 2-5/10"),
+            // The end is clamped to the body
+            ("bytes=8-20", b"89", "bytes 8-9/10"),
+            ("bytes=7-", b"789", "bytes 7-9/10"),
+            ("bytes=-3", b"789", "bytes 7-9/10"),
+            ("bytes=-50", b"0123456789", "bytes 0-9/10"),
+        ] {
+            let response = ranged(Some(range));
+            assert_eq!(response.status_code, 206, "{}", range);
+            assert_eq!(response.body, body, "{}", range);
+            assert_eq!(content_range(&response), Some(content), "{}", range);
+            assert_eq!(response.headers.get("Content-Length"), Some(&body.len().to_string()), "{}", range);
+        }
+    }
+
+    #[test]
+    fn unsatisfiable_ranges_get_416() {
+        for range in ["bytes=10-", "bytes=10-12", "bytes=-0"] {
+            let response = ranged(Some(range));
+            assert_eq!(response.status_code, 416, "{}", range);
+            assert!(response.body.is_empty(), "{}", range);
+            assert_eq!(content_range(&response), Some("bytes */10"), "{}", range);
+        }
+    }
+
+    #[test]
+    fn missing_or_unsupported_ranges_send_the_whole_body() {
+        for range in [None, Some("bytes=0-1,4-5"), Some("bytes=5-2"), Some("items=0-1"), Some("bytes=x-")] {
+            let response = ranged(range);
+            assert_eq!(response.status_code, 200, "{:?}", range);
+            assert_eq!(response.body, b"0123456789", "{:?}", range);
+            assert_eq!(response.headers.get("Accept-Ranges").map(String::as_str), Some("bytes"));
+        }
+    }
+
+    #[test]
+    fn non_utf8_header_values_are_rejected() {
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Disposition: attachment; filename=\"caf\xe9.txt\"\r\n\r\n";
+        assert!(matches!(Request::parse(&raw[..], &limits()), Err(ParseError::InvalidRequest)));
+        let raw = "POST /upload HTTP/1.1\r\nContent-Disposition: attachment; filename=\"café.txt\"\r\n\r\n";
+        let request = parse(raw).unwrap();
+        assert_eq!(request.headers.get("Content-Disposition").map(String::as_str), Some("attachment; filename=\"café.txt\""));
+    }
+
+    // Holds back a partial line until the rest of it, or the end of the body, arrives
+    struct WholeLines(Vec<u8>);
+
+    impl BodyTransform for WholeLines {
+        fn transform(&mut self, input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+            self.0.extend_from_slice(input);
+            if let Some(end) = self.0.iter().rposition(|&b| b == b'\n') {
+                output.extend(self.0.drain(..=end));
+            }
+            Ok(())
+        }
+
+        fn finish(&mut self, output: &mut Vec<u8>) -> io::Result<()> {
+            output.append(&mut self.0);
+            Ok(())
+        }
+    }
+
+    fn bracket(input: &[u8], output: &mut Vec<u8>) -> io::Result<()> {
+        output.push(b'[');
+        output.extend_from_slice(input);
+        output.push(b']');
+        Ok(())
+    }
+
+    fn transformed(transforms: Vec<Box<dyn BodyTransform>>) -> String {
+        let mut out = Vec::new();
+        let mut writer = ChunkedWriter::identity(&mut out).with_transforms(transforms);
+        for part in ["hello wo", "rld\nbye"] {
+            writer.write_all(part.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn composes_transforms_in_the_order_pushed() {
+        // Held-back output still passes through the transforms after it
+        assert_eq!(transformed(vec![Box::new(WholeLines(Vec::new())), Box::new(bracket)]), "[hello world\n][bye]");
+        assert_eq!(transformed(vec![Box::new(bracket), Box::new(WholeLines(Vec::new()))]), "[hello wo][rld\nbye]");
+    }
+
+    #[test]
+    fn pushed_transforms_switch_the_body_to_chunked() {
+        let mut response = Response::ok("text/plain", b"body".to_vec());
+        response.push_transform(WholeLines(Vec::new()));
+        response.push_transform(bracket);
+        assert!(!response.headers.contains_key("Content-Length"));
+        assert_eq!(response.headers.get("Transfer-Encoding").map(String::as_str), Some("chunked"));
+        assert_eq!(response.take_transforms().len(), 2);
+
+        let mut no_content = Response::no_content();
+        no_content.push_transform(bracket);
+        assert!(no_content.take_transforms().is_empty());
+    }
+
+    #[test]
+    fn header_lookups_ignore_the_name_case() {
+        let request = parse("POST / HTTP/1.1\r\nexpect: 100-Continue\r\nX-Custom: a\r\n\r\n").unwrap();
+        assert!(request.expects_continue());
+        assert_eq!(request.header("EXPECT"), Some("100-Continue"));
+        assert_eq!(request.header("x-custom"), Some("a"));
+        assert_eq!(request.header("Missing"), None);
+        assert!(!parse("POST / HTTP/1.1\r\nexpect: something-else\r\n\r\n").unwrap().expects_continue());
+    }
+
+    // Not a correctness check: `cargo test --release -- --ignored --nocapture head_parsing_throughput`
+    #[test]
+    #[ignore]
+    fn head_parsing_throughput() {
+        let raw = b"GET /users/42?page=2 HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/8.5.0\r\nAccept: */*\r\nConnection: keep-alive\r\n\r\n";
+        const REQUESTS: usize = 200_000;
+        let start = std::time::Instant::now();
+        for _ in 0..REQUESTS {
+            std::hint::black_box(Request::parse_head(std::hint::black_box(&raw[..])).unwrap());
+        }
+        println!("{:.0} heads/sec", REQUESTS as f64 / start.elapsed().as_secs_f64());
+    }
+
+    #[test]
+    fn bad_request_messages_are_escaped() {
+        let response = Response::bad_request("<script>alert('x')</script> & more");
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("<p>&lt;script&gt;alert(&#x27;x&#x27;)&lt;/script&gt; &amp; more</p>"), "{}", body);
+        assert!(!body.contains("<script"));
+    }
+
+    #[test]
+    fn query_parameters_are_capped_before_decoding() {
+        let at_limit = format!("GET /?{} HTTP/1.1\r\n\r\n", ["p=1"; 3].join("&"));
+        assert_eq!(Request::parse_head_limited(at_limit.as_bytes(), 3).unwrap().query.len(), 1);
+        // Past the cap even a malformed escape is not looked at
+        let over = format!("GET /?{}&bad=%zz HTTP/1.1\r\n\r\n", ["p=1"; 3].join("&"));
+        assert!(matches!(Request::parse_head_limited(over.as_bytes(), 3), Err(ParseError::TooManyQueryParams)));
+        // Empty pairs are not entries
+        assert!(Request::parse_head_limited(&b"GET /?a&&&b HTTP/1.1\r\n\r\n"[..], 2).is_ok());
+
+        let default = format!("GET /?{} HTTP/1.1\r\n\r\n", vec!["p"; DEFAULT_MAX_QUERY_PARAMS + 1].join("&"));
+        assert!(matches!(parse(&default), Err(ParseError::TooManyQueryParams)));
+    }
+}
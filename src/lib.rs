@@ -0,0 +1,20 @@
+// The server as a library, so an embedding program can register its own routes, checks and
+// middleware; main.rs is the standalone binary built on it
+pub mod threadpool;
+pub mod server;
+pub mod http;
+pub mod config;
+pub mod middleware;
+pub mod metrics;
+pub mod health;
+pub mod body_parser;
+pub mod cli;
+pub mod access_log;
+pub mod trace_context;
+mod template;
+mod spool;
+mod proxy;
+mod static_files;
+mod coalesce;
+mod router;
+mod upstream_pool;
@@ -1,36 +1,72 @@
-mod threadpool;
-mod server;
-mod http;
-mod config;
-mod middleware;
-
-use server::Server;
+use web_server::{cli, config, middleware, server, threadpool};
+use server::{Server, ServerError};
 use std::process;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc};
 use log::{info, error};
 use env_logger::Env;
-use config::Config;
-use middleware::{LoggingMiddleware, SecurityHeadersMiddleware, ErrorHandlingMiddleware};
+use config::{Config, ConfigError};
+use cli::{CliArgs, USAGE};
+use middleware::{DEFAULT_LOG_FORMAT, LoggingMiddleware, SecurityHeadersMiddleware, DefaultHeadersMiddleware, CorsMiddleware, TracingMiddleware, ResponseTimeMiddleware, RewriteMiddleware};
 use std::path::Path;
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::panic;
+use std::thread;
 
 fn main() {
-    // Load configuration
-    let config = match Config::from_file(Path::new("config.json")) {
-        Ok(config) => config,
-        Err(_) => {
-            info!("No config file found, using default configuration");
-            Config::default()
+    let args = match CliArgs::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return;
+    }
+
+    // Load configuration; a missing default file means defaults, a broken or missing
+    // explicitly requested one is fatal. Command-line flags override the file.
+    let config_path = args.config_path.as_deref().unwrap_or(Path::new("config.json"));
+    let (mut config, config_missing) = match Config::from_file(config_path) {
+        Ok(config) => (config, false),
+        Err(ConfigError::NotFound) if args.config_path.is_none() => (Config::default(), true),
+        Err(e) => {
+            // The logger is configured from the file, so report this directly
+            eprintln!("Error loading {}: {}", config_path.display(), e);
+            process::exit(1);
         }
     };
+    args.apply(&mut config);
+
+    // Dry run for deploy pipelines: no logger, listener or workers are set up
+    if args.check_config {
+        match config.validate() {
+            Ok(()) => {
+                println!("Configuration OK ({})", if config_missing { "defaults" } else { "loaded from file" });
+                return;
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
 
     // Initialize logger
     env_logger::Builder::from_env(Env::default().default_filter_or(&config.log_level))
         .format_timestamp_millis()
         .init();
 
+    install_panic_hook();
+
+    if config_missing {
+        info!("No config file found, using default configuration");
+    }
+
     info!("Starting HTTP server...");
 
-    let server = match Server::new(&config.address(), config.workers) {
+    let server = match Server::new(&config) {
         Ok(server) => server,
         Err(e) => {
             error!("Failed to start server: {:?}", e);
@@ -39,38 +75,90 @@ fn main() {
     };
 
     // Add middleware
+    let access_log = server.access_log();
     let server = server
-        .with_middleware(Box::new(LoggingMiddleware))
-        .with_middleware(Box::new(SecurityHeadersMiddleware))
-        .with_middleware(Box::new(ErrorHandlingMiddleware));
+        .with_middleware(Box::new(
+            LoggingMiddleware::with_format(config.log_format.as_deref().unwrap_or(DEFAULT_LOG_FORMAT))
+                .with_slow_threshold(config.slow_request_threshold_ms)
+                .with_buffer(access_log),
+        ))
+        .with_middleware(Box::new(SecurityHeadersMiddleware::with_config(config.security_headers.clone())))
+        .with_middleware(Box::new(DefaultHeadersMiddleware::new(config.default_headers.clone())))
+        .with_status_range_interceptor(400..=599, |_request, response| {
+            error!("Error response: {} - {}", response.status_code, response.status_text);
+        });
+
+    let server = if config.tracing {
+        server.with_middleware(Box::new(TracingMiddleware))
+    } else {
+        server
+    };
 
-    let server = Arc::new(Mutex::new(server));
+    let server = match config.response_time_header.as_deref() {
+        Some(header) => server.with_middleware(Box::new(ResponseTimeMiddleware::new(header))),
+        None => server,
+    };
+
+    let server = if config.rewrites.is_empty() {
+        server
+    } else {
+        server.with_middleware(Box::new(RewriteMiddleware::new(config.rewrites.clone())))
+    };
+
+    let server = match config.cors.clone() {
+        Some(cors) => server.with_middleware(Box::new(CorsMiddleware::new(cors))),
+        None => server,
+    };
+
+    let server = Arc::new(server);
     let server_clone = Arc::clone(&server);
 
     info!("Server available at http://{}", config.address());
     info!("Press Ctrl+C to stop the server");
 
-    // Handle graceful shutdown
+    // Handle graceful shutdown: the handler drains in-flight requests while run() returns
+    // as soon as accepting stops, so main waits for the drain before exiting
+    let (drained_tx, drained_rx) = mpsc::channel();
     ctrlc::set_handler(move || {
-        info!("Shutting down server...");
-        if let Ok(guard) = server_clone.lock() {
-            if let Err(e) = guard.shutdown() {
-                error!("Error during shutdown: {:?}", e);
+        match server_clone.shutdown() {
+            Ok(report) => {
+                let _ = drained_tx.send(report);
             }
+            Err(ServerError::ShuttingDown) => info!("Shutdown already in progress"),
+            Err(e) => error!("Error during shutdown: {:?}", e),
         }
-        process::exit(0);
     }).expect("Error setting Ctrl-C handler");
 
-    let guard = match server.lock() {
-        Ok(guard) => guard,
-        Err(e) => {
-            error!("Failed to lock server: {}", e);
-            process::exit(1);
-        }
-    };
-
-    if let Err(e) = guard.run() {
+    if let Err(e) = server.run() {
         error!("Server error: {:?}", e);
         process::exit(1);
     }
+
+    if let Ok(report) = drained_rx.recv() {
+        if report.timed_out {
+            // Exiting ends the requests that are still running
+            error!("Shutdown timed out: {}", report);
+            process::exit(1);
+        }
+        info!("Shutdown complete: {}", report);
+    }
+}
+
+// Routes panics through the logger so worker crashes show up next to the request logs.
+// A backtrace is included when RUST_BACKTRACE enables capturing one.
+fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let thread = thread::current();
+        let name = thread.name().unwrap_or("<unnamed>");
+        let message = threadpool::panic_message(info.payload());
+        let location = info.location()
+            .map_or_else(String::new, |l| format!(" at {}:{}", l.file(), l.line()));
+
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            error!("Thread '{}' panicked{}: {}\n{}", name, location, message, backtrace);
+        } else {
+            error!("Thread '{}' panicked{}: {}", name, location, message);
+        }
+    }));
 }
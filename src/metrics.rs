@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+pub trait MetricsSink: Send + Sync {
+    fn record_connection(&self);
+    fn record_request(&self);
+    fn record_error(&self);
+    fn record_duration(&self, ms: u64);
+    fn record_queue_wait(&self, wait: Duration);
+    fn snapshot(&self) -> MetricsSnapshot;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MetricsSnapshot {
+    pub connections: usize,
+    pub requests: usize,
+    pub errors: usize,
+    pub total_duration_ms: u64,
+    pub timed_requests: usize,
+    pub total_queue_wait_us: u64,
+    pub max_queue_wait_us: u64,
+    pub queued_jobs: usize,
+}
+
+impl MetricsSnapshot {
+    pub fn requests_per_connection(&self) -> f64 {
+        if self.connections == 0 {
+            0.0
+        } else {
+            self.requests as f64 / self.connections as f64
+        }
+    }
+
+    pub fn average_duration_ms(&self) -> f64 {
+        if self.timed_requests == 0 {
+            0.0
+        } else {
+            self.total_duration_ms as f64 / self.timed_requests as f64
+        }
+    }
+
+    pub fn average_queue_wait_ms(&self) -> f64 {
+        if self.queued_jobs == 0 {
+            0.0
+        } else {
+            self.total_queue_wait_us as f64 / self.queued_jobs as f64 / 1000.0
+        }
+    }
+}
+
+// Percentage of requests that did not error; 100% before any request has been seen.
+// Errors not tied to a request (e.g. failed accepts) can outnumber requests, so clamp at 0.
+pub fn success_rate(requests: usize, errors: usize) -> f64 {
+    if requests == 0 {
+        return 100.0;
+    }
+    (100.0 * (1.0 - errors as f64 / requests as f64)).max(0.0)
+}
+
+// Independent statistics counters that order nothing else, so every access is Relaxed
+#[derive(Default)]
+pub struct InMemoryMetrics {
+    connections: AtomicUsize,
+    requests: AtomicUsize,
+    errors: AtomicUsize,
+    total_duration_ms: AtomicU64,
+    timed_requests: AtomicUsize,
+    total_queue_wait_us: AtomicU64,
+    max_queue_wait_us: AtomicU64,
+    queued_jobs: AtomicUsize,
+}
+
+impl MetricsSink for InMemoryMetrics {
+    fn record_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_duration(&self, ms: u64) {
+        self.total_duration_ms.fetch_add(ms, Ordering::Relaxed);
+        self.timed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_queue_wait(&self, wait: Duration) {
+        let us = wait.as_micros().min(u64::MAX as u128) as u64;
+        self.total_queue_wait_us.fetch_add(us, Ordering::Relaxed);
+        self.max_queue_wait_us.fetch_max(us, Ordering::Relaxed);
+        self.queued_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            connections: self.connections.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            total_duration_ms: self.total_duration_ms.load(Ordering::Relaxed),
+            timed_requests: self.timed_requests.load(Ordering::Relaxed),
+            total_queue_wait_us: self.total_queue_wait_us.load(Ordering::Relaxed),
+            max_queue_wait_us: self.max_queue_wait_us.load(Ordering::Relaxed),
+            queued_jobs: self.queued_jobs.load(Ordering::Relaxed),
+        }
+    }
+}
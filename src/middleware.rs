@@ -1,39 +1,196 @@
-use crate::http::{Request, Response};
-use log::{info, error};
-use std::time::Instant;
+use crate::http::{reason_phrase, Method, Request, Response};
+use log::info;
 use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use crate::config::{CorsConfig, RewriteRule, SecurityHeadersConfig};
+use crate::trace_context::TraceContext;
+use crate::access_log::{AccessLogBuffer, AccessLogEntry};
 
 pub trait Middleware: Send + Sync {
     fn process(&self, request: &mut Request) -> Option<Response>;
     fn after(&self, request: &Request, response: &mut Response);
+
+    // Runs as soon as the request head is parsed, before the body is read and before the checks
+    // that depend on the path (body size limits, Content-Type allowlists, 100-continue), so a
+    // path changed here is the one those checks and routing see
+    fn rewrite(&self, _request: &mut Request) -> Rewrite {
+        Rewrite::Route
+    }
+
+    // Runs once the response has been written, in the same order as after(), for middleware
+    // that reports what actually went out, such as the size of a streamed or transformed body
+    fn completed(&self, _request: &Request, _sent: &Sent) {}
+
+    // Lower priorities run their process() earlier and their after() later
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+// What a rewrite() hook decided for the request
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rewrite {
+    // Check and route the (possibly rewritten) path as usual
+    Route,
+    // This middleware's process() will answer the request itself, e.g. with a redirect, so
+    // checks that would answer for the route instead are skipped
+    Answer,
+}
+
+// What reached the client, for completed() hooks
+pub struct Sent {
+    pub status: u16,
+    // Everything written after the head, including any chunked framing
+    pub body_bytes: u64,
+}
+
+pub const DEFAULT_LOG_FORMAT: &str = "$time $method $path $status ${duration_ms}ms";
+
+pub struct LoggingMiddleware {
+    format: String,
+    // When set, only requests at least this slow are logged, plus every 4xx/5xx
+    slow_threshold_ms: Option<u64>,
+    // Also keeps recent entries for GET /admin/logs
+    buffer: Option<Arc<AccessLogBuffer>>,
+}
+
+impl LoggingMiddleware {
+    pub fn new() -> Self {
+        Self::with_format(DEFAULT_LOG_FORMAT)
+    }
+
+    pub fn with_format(format: &str) -> Self {
+        Self { format: format.to_string(), slow_threshold_ms: None, buffer: None }
+    }
+
+    pub fn with_buffer(mut self, buffer: Option<Arc<AccessLogBuffer>>) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
+    pub fn with_slow_threshold(mut self, threshold_ms: Option<u64>) -> Self {
+        self.slow_threshold_ms = threshold_ms;
+        self
+    }
+
+    fn variable(name: &str, request: &Request, sent: &Sent, duration: i64) -> Option<String> {
+        let value = match name {
+            "time" => Utc::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+            "method" => request.method.as_str().to_string(),
+            "path" => request.target(),
+            "status" => sent.status.to_string(),
+            "duration_ms" => duration.to_string(),
+            "remote_addr" => request.remote_addr.map_or("-".to_string(), |a| a.ip().to_string()),
+            "request_id" => request.headers.get("X-Request-Id").cloned().unwrap_or_else(|| "-".to_string()),
+            "bytes_sent" => sent.body_bytes.to_string(),
+            "trace_id" => request.extensions.get::<TraceContext>()
+                .map_or_else(|| "-".to_string(), |trace| trace.trace_id.clone()),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Expands `$name` and `${name}` variables; unknown variables are kept as written
+    fn render(&self, request: &Request, sent: &Sent, duration: i64) -> String {
+        let mut line = String::with_capacity(self.format.len());
+        let mut rest = self.format.as_str();
+
+        while let Some(pos) = rest.find('$') {
+            line.push_str(&rest[..pos]);
+            let after = &rest[pos + 1..];
+
+            let (name, consumed) = if let Some(braced) = after.strip_prefix('{') {
+                match braced.find('}') {
+                    Some(end) => (&braced[..end], end + 2),
+                    None => ("", 0),
+                }
+            } else {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                (&after[..end], end)
+            };
+
+            match LoggingMiddleware::variable(name, request, sent, duration) {
+                Some(value) => line.push_str(&value),
+                None => line.push_str(&rest[pos..pos + 1 + consumed]),
+            }
+            rest = &after[consumed..];
+        }
+
+        line.push_str(rest);
+        line
+    }
 }
 
-pub struct LoggingMiddleware;
+impl Default for LoggingMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct AccessLogTimer(Instant);
 
 impl Middleware for LoggingMiddleware {
     fn process(&self, request: &mut Request) -> Option<Response> {
-        request.headers.insert("x-start-time".to_string(), Instant::now().elapsed().as_millis().to_string());
+        request.extensions.insert(AccessLogTimer(Instant::now()));
         None
     }
 
-    fn after(&self, request: &Request, response: &mut Response) {
-        let start_time = request.headers.get("x-start-time")
-            .and_then(|t| t.parse::<u128>().ok())
-            .unwrap_or(0);
-        let duration = Instant::now().elapsed().as_millis() - start_time;
-        
-        info!(
-            "{} {:?} {} {} {}ms",
-            Utc::now().format("%Y-%m-%d %H:%M:%S"),
-            request.method,
-            request.path,
-            response.status_code,
-            duration
-        );
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+
+    // Logged once written, so the duration includes sending the body and the size is what
+    // the writer actually sent
+    fn completed(&self, request: &Request, sent: &Sent) {
+        let duration = request.extensions.get::<AccessLogTimer>()
+            .map_or(0, |AccessLogTimer(start)| start.elapsed().as_millis() as i64);
+
+        let fast = self.slow_threshold_ms.is_some_and(|threshold| duration < threshold as i64);
+        if fast && sent.status < 400 {
+            return;
+        }
+        let line = self.render(request, sent, duration);
+        info!("{}", line);
+
+        if let Some(buffer) = &self.buffer {
+            buffer.push(AccessLogEntry {
+                time: Utc::now().to_rfc3339(),
+                method: request.method.as_str().to_string(),
+                path: request.target(),
+                status: sent.status,
+                duration_ms: duration,
+                remote_addr: request.remote_addr.map(|a| a.ip().to_string()),
+                line,
+            });
+        }
     }
 }
 
-pub struct SecurityHeadersMiddleware;
+pub struct SecurityHeadersMiddleware {
+    config: SecurityHeadersConfig,
+}
+
+impl SecurityHeadersMiddleware {
+    pub fn new() -> Self {
+        Self::with_config(SecurityHeadersConfig::default())
+    }
+
+    pub fn with_config(config: SecurityHeadersConfig) -> Self {
+        Self { config }
+    }
+}
+
+impl Default for SecurityHeadersMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl Middleware for SecurityHeadersMiddleware {
     fn process(&self, _request: &mut Request) -> Option<Response> {
@@ -41,35 +198,298 @@ impl Middleware for SecurityHeadersMiddleware {
     }
 
     fn after(&self, _request: &Request, response: &mut Response) {
+        for (name, value) in self.config.headers() {
+            response.headers.insert(name.to_string(), value.to_string());
+        }
+    }
+}
+
+pub struct DefaultHeadersMiddleware {
+    headers: HashMap<String, String>,
+}
+
+impl DefaultHeadersMiddleware {
+    pub fn new(headers: HashMap<String, String>) -> Self {
+        Self { headers }
+    }
+}
+
+impl Middleware for DefaultHeadersMiddleware {
+    fn process(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    fn after(&self, _request: &Request, response: &mut Response) {
+        for (key, value) in &self.headers {
+            let already_set = response.headers.keys().any(|k| k.eq_ignore_ascii_case(key));
+            if !already_set {
+                response.headers.insert(key.clone(), value.clone());
+            }
+        }
+    }
+}
+
+pub struct CorsMiddleware {
+    config: CorsConfig,
+}
+
+impl CorsMiddleware {
+    pub fn new(config: CorsConfig) -> Self {
+        Self { config }
+    }
+
+    fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.config.allowed_origins.iter().any(|o| o == "*") {
+            Some("*".to_string())
+        } else if self.config.allowed_origins.iter().any(|o| o == origin) {
+            Some(origin.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn process(&self, request: &mut Request) -> Option<Response> {
+        let is_preflight = request.method == Method::OPTIONS
+            && request.headers.contains_key("Access-Control-Request-Method");
+        if !is_preflight {
+            return None;
+        }
+        let allowed_origin = self.allow_origin(request.headers.get("Origin")?)?;
+
+        let mut response = Response::no_content();
+        response.headers.insert("Access-Control-Allow-Origin".to_string(), allowed_origin);
         response.headers.insert(
-            "X-Content-Type-Options".to_string(),
-            "nosniff".to_string(),
-        );
-        response.headers.insert(
-            "X-Frame-Options".to_string(),
-            "DENY".to_string(),
+            "Access-Control-Allow-Methods".to_string(),
+            self.config.allowed_methods.join(", "),
         );
         response.headers.insert(
-            "X-XSS-Protection".to_string(),
-            "1; mode=block".to_string(),
+            "Access-Control-Allow-Headers".to_string(),
+            self.config.allowed_headers.join(", "),
         );
+        if let Some(max_age) = self.config.max_age_seconds {
+            response.headers.insert("Access-Control-Max-Age".to_string(), max_age.to_string());
+        }
+        Some(response)
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let allowed_origin = request.headers.get("Origin").and_then(|o| self.allow_origin(o));
+        if let Some(origin) = allowed_origin {
+            if origin != "*" {
+                response.add_vary("Origin");
+            }
+            response.headers.insert("Access-Control-Allow-Origin".to_string(), origin);
+        }
     }
 }
 
-pub struct ErrorHandlingMiddleware;
+struct ResponseTimer(Instant);
 
-impl Middleware for ErrorHandlingMiddleware {
-    fn process(&self, _request: &mut Request) -> Option<Response> {
+// Reports handling time to the client. `Server-Timing` gets the `app;dur=<ms>` form that
+// browser devtools understand; any other header name gets a plain `<ms>ms` value.
+pub struct ResponseTimeMiddleware {
+    header: String,
+}
+
+impl ResponseTimeMiddleware {
+    pub fn new(header: &str) -> Self {
+        Self { header: header.to_string() }
+    }
+}
+
+impl Middleware for ResponseTimeMiddleware {
+    fn process(&self, request: &mut Request) -> Option<Response> {
+        request.extensions.insert(ResponseTimer(Instant::now()));
         None
     }
 
-    fn after(&self, _request: &Request, response: &mut Response) {
-        if response.status_code >= 400 {
-            error!(
-                "Error response: {} - {}",
-                response.status_code,
-                response.status_text
-            );
+    // Outermost, so the time covers the other middleware as well as the handler
+    fn priority(&self) -> i32 {
+        i32::MIN
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let Some(ResponseTimer(start)) = request.extensions.get::<ResponseTimer>() else {
+            return;
+        };
+        let elapsed_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+        if self.header.eq_ignore_ascii_case("Server-Timing") {
+            let metric = format!("app;dur={:.3}", elapsed_ms);
+            let value = match response.headers.get(&self.header) {
+                Some(existing) => format!("{}, {}", existing, metric),
+                None => metric,
+            };
+            response.headers.insert(self.header.clone(), value);
+        } else {
+            response.headers.insert(self.header.clone(), format!("{:.0}ms", elapsed_ms));
+        }
+    }
+}
+
+// Joins incoming W3C traces (or starts one) and rewrites `traceparent` on the request
+// so anything forwarded downstream, such as proxied calls, carries this server's span
+pub struct TracingMiddleware;
+
+impl Middleware for TracingMiddleware {
+    fn process(&self, request: &mut Request) -> Option<Response> {
+        let incoming = request.headers.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("traceparent"))
+            .map(|(_, value)| value.clone());
+        let context = TraceContext::from_traceparent(incoming.as_deref());
+
+        request.headers.retain(|name, _| !name.eq_ignore_ascii_case("traceparent"));
+        request.headers.insert("traceparent".to_string(), context.traceparent());
+        request.extensions.insert(context);
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if let Some(context) = request.extensions.get::<TraceContext>() {
+            response.headers.insert("X-Trace-Id".to_string(), context.trace_id.clone());
+        }
+    }
+}
+
+// Rewrites request paths before routing, so handlers and route matching see the new path.
+// The first matching rule wins; redirect rules answer with a Location header from process().
+pub struct RewriteMiddleware {
+    rules: Vec<RewriteRule>,
+}
+
+// A redirect decided in rewrite(), answered in process()
+struct PendingRedirect {
+    status: u16,
+    location: String,
+}
+
+impl RewriteMiddleware {
+    pub fn new(rules: Vec<RewriteRule>) -> Self {
+        Self { rules }
+    }
+}
+
+impl Middleware for RewriteMiddleware {
+    fn rewrite(&self, request: &mut Request) -> Rewrite {
+        let Some((rule, target)) = self.rules.iter().find_map(|rule| rule.apply(&request.path).map(|target| (rule, target))) else {
+            return Rewrite::Route;
+        };
+
+        match rule.redirect {
+            Some(status) => {
+                let location = match request.raw_query() {
+                    Some(query) if !target.contains('?') => format!("{}?{}", target, query),
+                    _ => target,
+                };
+                request.extensions.insert(PendingRedirect { status, location });
+                Rewrite::Answer
+            }
+            None => {
+                request.set_path(&target);
+                Rewrite::Route
+            }
         }
     }
-} 
\ No newline at end of file
+
+    fn process(&self, request: &mut Request) -> Option<Response> {
+        let redirect = request.extensions.remove::<PendingRedirect>()?;
+        let mut response = Response::new(redirect.status, reason_phrase(redirect.status), "text/plain", Vec::new());
+        response.headers.insert("Location".to_string(), redirect.location);
+        Some(response)
+    }
+
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+
+    // Ahead of everything that looks at the path, except the response timer
+    fn priority(&self) -> i32 {
+        i32::MIN + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(from: &str, to: &str, redirect: Option<u16>) -> RewriteRule {
+        RewriteRule { from: from.to_string(), to: to.to_string(), redirect }
+    }
+
+    fn get(target: &str) -> Request {
+        Request::parse_head(format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", target).as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn strips_a_prefix() {
+        let rewrites = RewriteMiddleware::new(vec![rule("/v1/*", "/$1", None)]);
+        let mut request = get("/v1/users?page=2");
+        assert_eq!(rewrites.rewrite(&mut request), Rewrite::Route);
+        assert_eq!(request.path, "/users");
+        assert_eq!(request.query.get("page").map(String::as_str), Some("2"));
+        assert!(rewrites.process(&mut request).is_none());
+
+        let mut request = get("/v2/users");
+        rewrites.rewrite(&mut request);
+        assert_eq!(request.path, "/v2/users");
+    }
+
+    #[test]
+    fn substitutes_captures_in_order() {
+        let rewrites = RewriteMiddleware::new(vec![
+            rule("/users/*/posts/*", "/posts/$2?user=$1", None),
+            rule("/*", "/v2/$1", None),
+        ]);
+        let mut request = get("/users/7/posts/42");
+        rewrites.rewrite(&mut request);
+        assert_eq!(request.path, "/posts/42");
+        assert_eq!(request.raw_query(), Some("user=7"));
+
+        // Only the first matching rule applies
+        let mut request = get("/about");
+        rewrites.rewrite(&mut request);
+        assert_eq!(request.path, "/v2/about");
+    }
+
+    #[test]
+    fn redirects_keep_the_query_string() {
+        let rewrites = RewriteMiddleware::new(vec![rule("/old/*", "/new/$1", Some(301))]);
+        let mut request = get("/old/page?x=1");
+        assert_eq!(rewrites.rewrite(&mut request), Rewrite::Answer);
+        assert_eq!(request.path, "/old/page");
+
+        let response = rewrites.process(&mut request).unwrap();
+        assert_eq!(response.status_code, 301);
+        assert_eq!(response.status_text, "Moved Permanently");
+        assert_eq!(response.headers.get("Location").map(String::as_str), Some("/new/page?x=1"));
+        // Answered once; the redirect does not linger in the request
+        assert!(rewrites.process(&mut request).is_none());
+    }
+
+    #[test]
+    fn slow_threshold_skips_fast_successes_only() {
+        let buffer = Arc::new(AccessLogBuffer::new(10));
+        let logging = LoggingMiddleware::with_format("$path $status")
+            .with_slow_threshold(Some(50))
+            .with_buffer(Some(Arc::clone(&buffer)));
+        let ok = Sent { status: 200, body_bytes: 0 };
+
+        let mut fast = get("/fast");
+        logging.process(&mut fast);
+        logging.completed(&fast, &ok);
+
+        let mut slow = get("/slow");
+        logging.process(&mut slow);
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        logging.completed(&slow, &ok);
+
+        // Errors are logged however quick they were
+        let mut failed = get("/failed");
+        logging.process(&mut failed);
+        logging.completed(&failed, &Sent { status: 500, body_bytes: 0 });
+
+        let lines: Vec<String> = buffer.snapshot().into_iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, ["/slow 200", "/failed 500"]);
+        assert!(buffer.snapshot()[0].duration_ms >= 50);
+    }
+}
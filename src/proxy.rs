@@ -0,0 +1,514 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use log::{debug, warn};
+use crate::config::UpstreamPoolConfig;
+use crate::http::{copy_chunked_body, Method, ParseLimits, Request, Response};
+use crate::upstream_pool::UpstreamPool;
+
+const MAX_UPSTREAM_HEAD_SIZE: usize = 8192;
+const RELAY_BUFFER_SIZE: usize = 8192;
+
+// Connection-scoped headers that must not be forwarded in either direction
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "Connection",
+    "Keep-Alive",
+    "Proxy-Connection",
+    "Proxy-Authenticate",
+    "Proxy-Authorization",
+    "TE",
+    "Trailer",
+    "Transfer-Encoding",
+    "Upgrade",
+];
+
+// An upstream given as `http://host[:port][/base]`
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    host: String,
+    port: u16,
+    base_path: String,
+}
+
+impl Upstream {
+    pub fn parse(url: &str) -> Result<Upstream, String> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| format!("upstream '{}' must be an http:// URL", url))?;
+        let (authority, base_path) = match rest.find('/') {
+            Some(pos) => (&rest[..pos], rest[pos..].trim_end_matches('/')),
+            None => (rest, ""),
+        };
+        // An IPv6 literal is bracketed, e.g. `[::1]:9000`, so its colons are not the port's
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']')
+                    .ok_or_else(|| format!("upstream '{}' has an unclosed IPv6 address", url))?;
+                match after {
+                    "" => (host, None),
+                    _ => (host, Some(after.strip_prefix(':').unwrap_or(after))),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("upstream '{}' has an invalid port", url))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(format!("upstream '{}' has no host", url));
+        }
+        Ok(Upstream {
+            host: host.to_string(),
+            port,
+            base_path: base_path.to_string(),
+        })
+    }
+
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        if self.port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+}
+
+struct ResponseHead {
+    status_code: u16,
+    status_text: String,
+    // In the order received, keeping repeated fields such as Set-Cookie as separate lines
+    headers: Vec<(String, String)>,
+    http11: bool,
+}
+
+impl ResponseHead {
+    // Whether the upstream will take another request on this connection
+    fn keeps_alive(&self) -> bool {
+        let listed = connection_listed(self.headers.iter().map(|(name, value)| (name, value)));
+        let listed = |token: &str| listed.iter().any(|v| v.eq_ignore_ascii_case(token));
+        if self.http11 { !listed("close") } else { listed("keep-alive") }
+    }
+}
+
+pub struct ProxyHandler {
+    upstream: Upstream,
+    limits: ParseLimits,
+    pool: Arc<UpstreamPool>,
+}
+
+impl ProxyHandler {
+    pub fn new(upstream: Upstream, limits: ParseLimits, pool_config: UpstreamPoolConfig) -> Self {
+        let pool = UpstreamPool::new(&upstream.host, upstream.port, pool_config);
+        Self { upstream, limits, pool }
+    }
+
+    // Replays the request upstream and relays the answer. An upstream that times out is a 504,
+    // any other failure (refused, reset, malformed response) is a 502.
+    pub fn forward(&self, request: &Request) -> Response {
+        match self.try_forward(request) {
+            Ok(response) => response,
+            Err(e) if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock) => {
+                warn!("Proxying {} to {} timed out: {}", request.path, self.upstream.authority(), e);
+                Response::gateway_timeout()
+            }
+            Err(e) => {
+                warn!("Proxying {} to {} failed: {}", request.path, self.upstream.authority(), e);
+                Response::bad_gateway()
+            }
+        }
+    }
+
+    fn try_forward(&self, request: &Request) -> io::Result<Response> {
+        let body = request.load_body()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+
+        let head = self.request_head(request, body.len()).into_bytes();
+        debug!("Proxying {:?} {} to {}", request.method, request.path, self.upstream.authority());
+
+        let connection = self.pool.checkout()?;
+        let (mut reader, response_head) = match exchange(connection.stream, &head, &body) {
+            Ok(exchanged) => exchanged,
+            // The upstream may have closed a pooled connection just as it was picked; only
+            // requests that are safe to repeat are retried, on a fresh connection
+            Err(e) if connection.reused && is_idempotent(&request.method) => {
+                debug!("Pooled connection to {} failed ({}), retrying", self.upstream.authority(), e);
+                exchange(self.pool.connect()?, &head, &body)?
+            }
+            Err(e) => return Err(e),
+        };
+        let reusable = response_head.keeps_alive();
+        let ResponseHead { status_code, status_text, headers, .. } = response_head;
+        let has_body = request.method != Method::HEAD
+            && !(100..200).contains(&status_code)
+            && status_code != 204
+            && status_code != 304;
+
+        let chunked = header(&headers, "Transfer-Encoding").is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        let mut response = if has_body && chunked {
+            // Decoded chunk by chunk as it is relayed, then re-chunked by our own writer
+            let limits = self.limits.clone();
+            let pool = Arc::clone(&self.pool);
+            Response::stream(status_code, &status_text, "", move |writer| {
+                copy_chunked_body(&mut reader, &mut FlushEach(writer), &limits)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))?;
+                if reusable {
+                    release_to(&pool, reader);
+                }
+                Ok(())
+            })
+        } else if has_body {
+            let length = header(&headers, "Content-Length").and_then(|v| v.parse::<u64>().ok());
+            let pool = Arc::clone(&self.pool);
+            Response::stream(status_code, &status_text, "", move |writer| {
+                // Flush after every read so e.g. event streams reach the client as they arrive
+                let mut body = reader.take(length.unwrap_or(u64::MAX));
+                let mut buffer = [0; RELAY_BUFFER_SIZE];
+                loop {
+                    let n = match body.read(&mut buffer) {
+                        Ok(0) => {
+                            // A body delimited by EOF leaves nothing to reuse
+                            if reusable && length.is_some() && body.limit() == 0 {
+                                release_to(&pool, body.into_inner());
+                            }
+                            return Ok(());
+                        }
+                        Ok(n) => n,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(e),
+                    };
+                    writer.write_all(&buffer[..n])?;
+                    writer.flush()?;
+                }
+            })
+        } else {
+            if reusable {
+                self.release(reader);
+            }
+            Response::new(status_code, &status_text, "", Vec::new())
+        };
+
+        response.headers.remove("Content-Type");
+        if !has_body {
+            response.headers.remove("Content-Length");
+        }
+        // Our writer frames relayed bodies itself; a bodiless (e.g. HEAD) answer keeps the upstream length
+        let dropped = connection_listed(headers.iter().map(|(name, value)| (name, value)));
+        let mut relayed: Vec<String> = Vec::new();
+        for (name, value) in headers {
+            if is_hop_by_hop(&name, &dropped) || (has_body && name.eq_ignore_ascii_case("Content-Length")) {
+                continue;
+            }
+            if relayed.iter().any(|r| r.eq_ignore_ascii_case(&name)) {
+                response.append_header(&name, &value);
+                continue;
+            }
+            // The first upstream value (e.g. Server) replaces ours regardless of name casing
+            response.headers.retain(|existing, _| !existing.eq_ignore_ascii_case(&name));
+            response.headers.insert(name.clone(), value);
+            relayed.push(name);
+        }
+        Ok(response)
+    }
+
+    fn request_head(&self, request: &Request, body_len: usize) -> String {
+        let mut head = format!(
+            "{} {}{} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\n",
+            request.method.as_str(),
+            self.upstream.base_path,
+            request.target(),
+            self.upstream.authority(),
+            body_len,
+        );
+
+        let dropped = connection_listed(&request.headers);
+        for (name, value) in &request.headers {
+            let skip = is_hop_by_hop(name, &dropped)
+                // The proxy sets the forwarding headers itself; a client's own are not trusted
+                || ["Host", "Content-Length", "X-Forwarded-For", "X-Forwarded-Host", "X-Forwarded-Proto"]
+                    .iter()
+                    .any(|h| h.eq_ignore_ascii_case(name));
+            if !skip {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+
+        if let Some(addr) = request.remote_addr {
+            let forwarded_for = match request.header("X-Forwarded-For") {
+                Some(prior) => format!("{}, {}", prior, addr.ip()),
+                None => addr.ip().to_string(),
+            };
+            head.push_str(&format!("X-Forwarded-For: {}\r\n", forwarded_for));
+        }
+        if let Some(host) = request.header("Host") {
+            head.push_str(&format!("X-Forwarded-Host: {}\r\n", host));
+        }
+        head.push_str("X-Forwarded-Proto: http\r\n\r\n");
+        head
+    }
+
+    fn release(&self, reader: BufReader<TcpStream>) {
+        release_to(&self.pool, reader);
+    }
+}
+
+// Sends one request and reads the final response head. Interim 1xx responses (100 Continue,
+// 103 Early Hints) carry no body and are skipped; 101 would hand the connection over to
+// another protocol, which is never asked for since Upgrade is not forwarded.
+fn exchange(mut upstream: TcpStream, head: &[u8], body: &[u8]) -> io::Result<(BufReader<TcpStream>, ResponseHead)> {
+    upstream.write_all(head)?;
+    upstream.write_all(body)?;
+    upstream.flush()?;
+    let mut reader = BufReader::new(upstream);
+    loop {
+        let head = read_response_head(&mut reader)?;
+        match head.status_code {
+            101 => return Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected upstream protocol switch")),
+            100..=199 => debug!("Skipping interim {} response from upstream", head.status_code),
+            _ => return Ok((reader, head)),
+        }
+    }
+}
+
+// Flushes after every write so relayed data reaches the client as it arrives, e.g. for
+// event streams
+struct FlushEach<W>(W);
+
+impl<W: Write> Write for FlushEach<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.0.flush()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// Bytes already buffered past the response would be read as the start of the next one
+fn release_to(pool: &UpstreamPool, reader: BufReader<TcpStream>) {
+    if reader.buffer().is_empty() {
+        pool.checkin(reader.into_inner());
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(method, Method::GET | Method::HEAD | Method::PUT | Method::DELETE | Method::OPTIONS)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a String> {
+    headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v)
+}
+
+// Header names a peer listed in its Connection header are hop-by-hop as well
+fn connection_listed<'a>(headers: impl IntoIterator<Item = (&'a String, &'a String)>) -> Vec<String> {
+    headers.into_iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("Connection"))
+        .flat_map(|(_, value)| value.split(',').map(|v| v.trim().to_string()))
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+fn is_hop_by_hop(name: &str, dropped: &[String]) -> bool {
+    HOP_BY_HOP_HEADERS.iter().any(|h| h.eq_ignore_ascii_case(name))
+        || dropped.iter().any(|d| d.eq_ignore_ascii_case(name))
+}
+
+fn read_response_head(reader: &mut impl BufRead) -> io::Result<ResponseHead> {
+    let invalid = |what: &str| io::Error::new(io::ErrorKind::InvalidData, format!("invalid upstream {}", what));
+    let mut read = 0;
+    let mut next_line = |reader: &mut dyn BufRead| -> io::Result<String> {
+        let mut line = String::new();
+        let n = reader.take((MAX_UPSTREAM_HEAD_SIZE - read) as u64).read_line(&mut line)?;
+        read += n;
+        if n == 0 || !line.ends_with('\n') {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "upstream response head truncated or too large"));
+        }
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    };
+
+    let status_line = next_line(reader)?;
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().filter(|v| v.starts_with("HTTP/1.")).ok_or_else(|| invalid("status line"))?;
+    let http11 = version != "HTTP/1.0";
+    let status_code = parts.next().and_then(|c| c.parse().ok()).ok_or_else(|| invalid("status code"))?;
+    let status_text = parts.next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let line = next_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok(ResponseHead { status_code, status_text, headers, http11 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::thread;
+    use crate::http::ChunkedWriter;
+
+    // Answers requests with the given raw responses in order, across however many connections
+    // the proxy opens; returns the number of connections accepted so far alongside the handler
+    fn proxy_to(responses: &[&'static str]) -> (ProxyHandler, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let responses = Arc::new(Mutex::new(responses.iter().copied().collect::<VecDeque<_>>()));
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&accepted);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                counter.fetch_add(1, Ordering::SeqCst);
+                let responses = Arc::clone(&responses);
+                thread::spawn(move || loop {
+                    // Requests from the proxy are bodiless here, so the head is the whole request
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        if stream.read_line(&mut line).unwrap_or(0) == 0 {
+                            return;
+                        }
+                        if line == "\r\n" {
+                            break;
+                        }
+                    }
+                    let Some(response) = responses.lock().unwrap().pop_front() else { return };
+                    stream.get_mut().write_all(response.as_bytes()).unwrap();
+                });
+            }
+        });
+        let upstream = Upstream::parse(&format!("http://127.0.0.1:{}", port)).unwrap();
+        (ProxyHandler::new(upstream, ParseLimits::default(), UpstreamPoolConfig::default()), accepted)
+    }
+
+    fn get(path: &str) -> Request {
+        Request::parse_head(format!("GET {} HTTP/1.1\r\nHost: client\r\n\r\n", path).as_bytes()).unwrap()
+    }
+
+    fn body_of(mut response: Response) -> Vec<u8> {
+        let mut body = Vec::new();
+        match response.stream.take() {
+            Some(producer) => producer(&mut ChunkedWriter::identity(&mut body)).unwrap(),
+            None => body = response.body,
+        }
+        body
+    }
+
+    #[test]
+    fn streams_chunked_upstream_bodies() {
+        let (proxy, _) = proxy_to(&["HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n"]);
+        let response = proxy.forward(&get("/"));
+        assert_eq!(response.status_code, 200);
+        assert!(response.stream.is_some());
+        assert!(!response.headers.contains_key("Content-Length"));
+        assert_eq!(body_of(response), b"hello world");
+    }
+
+    #[test]
+    fn reuses_the_connection_after_a_chunked_body() {
+        let chunked = "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nok\r\n0\r\n\r\n";
+        let (proxy, accepted) = proxy_to(&[chunked, chunked]);
+        assert_eq!(body_of(proxy.forward(&get("/a"))), b"ok");
+        assert_eq!(body_of(proxy.forward(&get("/b"))), b"ok");
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn keeps_repeated_headers() {
+        let (proxy, _) = proxy_to(&["HTTP/1.1 200 OK\r\nSet-Cookie: a=1\r\nset-cookie: b=2\r\nContent-Length: 0\r\n\r\n"]);
+        let response = proxy.forward(&get("/"));
+        let mut cookies: Vec<&str> = response.header_lines()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("Set-Cookie"))
+            .map(|(_, value)| value.as_str())
+            .collect();
+        cookies.sort();
+        assert_eq!(cookies, ["a=1", "b=2"]);
+        let head = String::from_utf8(response.head_bytes()).unwrap();
+        assert!(head.contains("Set-Cookie: a=1\r\n") && head.contains("set-cookie: b=2\r\n"), "{}", head);
+    }
+
+    #[test]
+    fn skips_interim_responses() {
+        let (proxy, _) = proxy_to(&["HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a.css>; rel=preload\r\n\r\nHTTP/1.1 201 Created\r\nContent-Length: 4\r\n\r\ndone"]);
+        let response = proxy.forward(&get("/"));
+        assert_eq!(response.status_code, 201);
+        assert!(!response.headers.contains_key("Link"));
+        assert_eq!(body_of(response), b"done");
+    }
+
+    #[test]
+    fn refuses_an_upstream_protocol_switch() {
+        let (proxy, _) = proxy_to(&["HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n"]);
+        assert_eq!(proxy.forward(&get("/")).status_code, 502);
+    }
+
+    #[test]
+    fn forwarding_headers_are_read_whatever_their_case() {
+        let (proxy, _) = proxy_to(&[]);
+        let mut request = Request::parse_head(
+            &b"GET /page HTTP/1.1\r\nhost: example.com\r\nx-forwarded-for: 10.0.0.1\r\nx-forwarded-host: evil.test\r\nX-Forwarded-Proto: https\r\n\r\n"[..],
+        ).unwrap();
+        request.remote_addr = Some("192.0.2.7:5000".parse().unwrap());
+        let head = proxy.request_head(&request, 0);
+
+        assert!(head.contains("X-Forwarded-For: 10.0.0.1, 192.0.2.7\r\n"), "{}", head);
+        assert!(head.contains("X-Forwarded-Host: example.com\r\n"), "{}", head);
+        assert!(head.contains("X-Forwarded-Proto: http\r\n"), "{}", head);
+        let lower = head.to_ascii_lowercase();
+        assert_eq!(lower.matches("x-forwarded-for:").count(), 1, "{}", head);
+        assert_eq!(lower.matches("x-forwarded-host:").count(), 1, "{}", head);
+        assert_eq!(lower.matches("x-forwarded-proto:").count(), 1, "{}", head);
+        assert!(!head.contains("evil.test"));
+    }
+
+    #[test]
+    fn upstreams_may_be_ipv6_literals() {
+        let upstream = Upstream::parse("http://[::1]:9000/api").unwrap();
+        assert_eq!((upstream.host.as_str(), upstream.port, upstream.base_path.as_str()), ("::1", 9000, "/api"));
+        assert_eq!(upstream.authority(), "[::1]:9000");
+        let upstream = Upstream::parse("http://[2001:db8::2]").unwrap();
+        assert_eq!((upstream.host.as_str(), upstream.port), ("2001:db8::2", 80));
+        assert_eq!(upstream.authority(), "[2001:db8::2]");
+
+        let upstream = Upstream::parse("http://localhost:8081").unwrap();
+        assert_eq!((upstream.authority().as_str(), upstream.port), ("localhost:8081", 8081));
+        for invalid in ["http://[::1:9000", "http://[::1]x9000", "http://[::1]:port", "http://:80"] {
+            assert!(Upstream::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn proxies_to_an_ipv6_upstream() {
+        let Ok(listener) = TcpListener::bind("[::1]:0") else { return };
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let mut stream = BufReader::new(listener.accept().unwrap().0);
+            let mut line = String::new();
+            while stream.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.get_mut().write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nv6").unwrap();
+        });
+        let upstream = Upstream::parse(&format!("http://[::1]:{}", port)).unwrap();
+        let proxy = ProxyHandler::new(upstream, ParseLimits::default(), UpstreamPoolConfig::default());
+        assert_eq!(body_of(proxy.forward(&get("/"))), b"v6");
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use crate::http::Method;
+
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+// A `:param` route split into segments once, when it is registered
+struct Pattern<H> {
+    method: Method,
+    route: String,
+    segments: Vec<Segment>,
+    // Fixed segments before the first parameter
+    leading_static: usize,
+    param_count: usize,
+    handler: H,
+}
+
+impl<H> Pattern<H> {
+    // Segments must line up one to one, so `/users/:id` matches neither `/users/42/posts` nor
+    // `/users/42/`; a parameter never matches an empty segment
+    fn match_segments(&self, path: &[&str]) -> Option<HashMap<String, String>> {
+        if path.len() != self.segments.len() {
+            return None;
+        }
+        let mut params = HashMap::with_capacity(self.param_count);
+        for (expected, actual) in self.segments.iter().zip(path) {
+            match expected {
+                Segment::Static(text) if text != actual => return None,
+                Segment::Static(_) => {}
+                Segment::Param(_) if actual.is_empty() => return None,
+                Segment::Param(name) => {
+                    params.insert(name.clone(), actual.to_string());
+                }
+            }
+        }
+        Some(params)
+    }
+}
+
+// Exact routes are a hash lookup; `:param` routes are kept compiled and ordered so the first
+// match is the most specific one: the longest fixed prefix, then the fewest parameters
+pub struct RouteTable<H> {
+    routes: HashMap<(Method, String), H>,
+    patterns: Vec<Pattern<H>>,
+}
+
+impl<H> Default for RouteTable<H> {
+    fn default() -> Self {
+        RouteTable { routes: HashMap::new(), patterns: Vec::new() }
+    }
+}
+
+impl<H> RouteTable<H> {
+    // Replaces any handler already registered for the method and route
+    // Patterns are only matched as patterns, so a request for the literal `/users/:id` does
+    // not reach the handler without its parameters
+    pub fn insert(&mut self, (method, route): (Method, String), handler: H) {
+        if !route.contains("/:") {
+            self.routes.insert((method, route), handler);
+            return;
+        }
+        self.patterns.retain(|p| p.method != method || p.route != route);
+        let segments: Vec<Segment> = route.split('/')
+            .map(|segment| match segment.strip_prefix(':') {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Static(segment.to_string()),
+            })
+            .collect();
+        let leading_static = segments.iter().take_while(|s| matches!(s, Segment::Static(_))).count();
+        let param_count = segments.iter().filter(|s| matches!(s, Segment::Param(_))).count();
+        self.patterns.push(Pattern { method, route, segments, leading_static, param_count, handler });
+        // Longest fixed prefix first, then fewest parameters, ties broken by the pattern text
+        self.patterns.sort_by(|a, b| {
+            b.leading_static.cmp(&a.leading_static)
+                .then_with(|| a.param_count.cmp(&b.param_count))
+                .then_with(|| a.route.cmp(&b.route))
+        });
+    }
+
+    // Exact routes and patterns alike
+    pub fn keys(&self) -> impl Iterator<Item = (&Method, &str)> {
+        self.routes.keys()
+            .map(|(method, route)| (method, route.as_str()))
+            .chain(self.patterns.iter().map(|p| (&p.method, p.route.as_str())))
+    }
+
+    // An exact route wins over a `:param` pattern; returns the matched route text and the
+    // captured parameters along with the handler
+    pub fn find(&self, method: &Method, path: &str) -> Option<(&str, &H, HashMap<String, String>)> {
+        if let Some((route, handler)) = self.routes.get_key_value(&(method.clone(), path.to_string())) {
+            return Some((&route.1, handler, HashMap::new()));
+        }
+        let segments: Vec<&str> = path.split('/').collect();
+        self.find_pattern(method, &segments)
+    }
+
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        let segments: Vec<&str> = path.split('/').collect();
+        Method::ALL.iter()
+            .filter(|method| {
+                self.routes.contains_key(&((*method).clone(), path.to_string()))
+                    || self.find_pattern(method, &segments).is_some()
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn find_pattern(&self, method: &Method, segments: &[&str]) -> Option<(&str, &H, HashMap<String, String>)> {
+        self.patterns.iter()
+            .filter(|pattern| &pattern.method == method)
+            .find_map(|pattern| {
+                pattern.match_segments(segments).map(|params| (pattern.route.as_str(), &pattern.handler, params))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(routes: &[&'static str]) -> RouteTable<&'static str> {
+        let mut table = RouteTable::default();
+        for route in routes {
+            table.insert((Method::GET, route.to_string()), *route);
+        }
+        table
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn captures_a_single_param() {
+        let routes = table(&["/users/:id"]);
+        let (route, handler, captured) = routes.find(&Method::GET, "/users/42").unwrap();
+        assert_eq!((route, *handler), ("/users/:id", "/users/:id"));
+        assert_eq!(captured, params(&[("id", "42")]));
+
+        assert!(routes.find(&Method::GET, "/users/42/posts").is_none());
+        assert!(routes.find(&Method::GET, "/users").is_none());
+        assert!(routes.find(&Method::POST, "/users/42").is_none());
+    }
+
+    #[test]
+    fn captures_multiple_params() {
+        let routes = table(&["/users/:id", "/users/:user/posts/:post"]);
+        let (route, _, captured) = routes.find(&Method::GET, "/users/7/posts/99").unwrap();
+        assert_eq!(route, "/users/:user/posts/:post");
+        assert_eq!(captured, params(&[("user", "7"), ("post", "99")]));
+    }
+
+    #[test]
+    fn trailing_slashes_and_empty_segments_do_not_match() {
+        let routes = table(&["/users/:id"]);
+        assert!(routes.find(&Method::GET, "/users/42/").is_none());
+        assert!(routes.find(&Method::GET, "/users/").is_none());
+    }
+
+    #[test]
+    fn exact_routes_beat_params_and_fewer_params_win() {
+        let routes = table(&["/users/:id", "/users/me", "/:section/:id"]);
+        let (route, _, captured) = routes.find(&Method::GET, "/users/me").unwrap();
+        assert_eq!(route, "/users/me");
+        assert!(captured.is_empty());
+        assert_eq!(routes.find(&Method::GET, "/users/5").unwrap().0, "/users/:id");
+        assert_eq!(routes.find(&Method::GET, "/teams/5").unwrap().0, "/:section/:id");
+        assert_eq!(routes.allowed_methods("/users/5"), [Method::GET]);
+    }
+
+    #[test]
+    fn longer_fixed_prefixes_win_among_equal_param_counts() {
+        let routes = table(&["/:section/posts", "/users/:id"]);
+        assert_eq!(routes.find(&Method::GET, "/users/posts").unwrap().0, "/users/:id");
+        assert_eq!(routes.find(&Method::GET, "/teams/posts").unwrap().0, "/:section/posts");
+    }
+
+    #[test]
+    fn patterns_are_not_exact_routes() {
+        let routes = table(&["/users/:id"]);
+        let (_, _, captured) = routes.find(&Method::GET, "/users/:id").unwrap();
+        // Matched as a pattern, with the literal text as the parameter
+        assert_eq!(captured, params(&[("id", ":id")]));
+        let mut keys: Vec<_> = table(&["/users/:id", "/health"]).keys().map(|(_, route)| route.to_string()).collect();
+        keys.sort();
+        assert_eq!(keys, ["/health", "/users/:id"]);
+    }
+
+    // Not a correctness check: `cargo test --release -- --ignored --nocapture matching_throughput`
+    #[test]
+    #[ignore]
+    fn matching_throughput() {
+        let mut routes = RouteTable::default();
+        for route in ["/", "/health", "/readyz", "/stats", "/echo", "/robots.txt", "/sitemap.xml", "/admin/logs"] {
+            routes.insert((Method::GET, route.to_string()), ());
+        }
+        for resource in ["users", "teams", "posts", "orders", "invoices"] {
+            routes.insert((Method::GET, format!("/{}/:id", resource)), ());
+            routes.insert((Method::GET, format!("/{}/:id/comments/:comment", resource)), ());
+        }
+        let paths = ["/health", "/users/42", "/invoices/7/comments/3", "/missing/path", "/orders/9"];
+
+        const ROUNDS: usize = 200_000;
+        let start = std::time::Instant::now();
+        let mut matched = 0;
+        for _ in 0..ROUNDS {
+            for path in paths {
+                matched += usize::from(routes.find(&Method::GET, std::hint::black_box(path)).is_some());
+            }
+        }
+        let elapsed = start.elapsed();
+        assert_eq!(matched, ROUNDS * 4);
+        println!("{:.0} matches/sec", (ROUNDS * paths.len()) as f64 / elapsed.as_secs_f64());
+    }
+}
@@ -1,32 +1,278 @@
-use std::net::{TcpListener, TcpStream};
-use std::io::{self, Write, ErrorKind};
-use std::time::Duration;
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::{Arc, RwLock};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::io::{self, BufWriter, IoSlice, Read, Write, ErrorKind};
+use std::time::{Duration, Instant};
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::fmt;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
 use log::{info, warn, error, debug, trace};
 use chrono::Utc;
-use serde_json::json;
-use crate::threadpool::{ThreadPool, ThreadPoolError};
-use crate::http::{Request, Response, ParseError, Method};
-use crate::middleware::Middleware;
+use serde_json::{json, Value};
+use crate::threadpool::{panic_message, PoolOptions, ThreadPool, ThreadPoolError};
+use crate::http::{ChunkedWriter, Request, Response, RetryAfter, ParseError, ParseLimits, Method};
+use crate::middleware::{Middleware, Rewrite, Sent};
+use crate::config::{BodyPolicyConfig, Config, MetricsAuthConfig};
+use crate::metrics::{success_rate, InMemoryMetrics, MetricsSink};
+use crate::template;
+use crate::health::{HealthRegistry, HealthStatus};
+use crate::proxy::{ProxyHandler, Upstream};
+use crate::body_parser::{BodyError, BodyParsers};
+use crate::access_log::AccessLogBuffer;
+use crate::static_files::StaticFiles;
+use crate::coalesce::SingleFlight;
+use crate::router;
 
-const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 const MAX_CONSECUTIVE_ERRORS: usize = 10;
 const ERROR_RECOVERY_INTERVAL: Duration = Duration::from_secs(5);
+const INITIAL_RECOVERY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECOVERY_BACKOFF: Duration = Duration::from_secs(60);
 const TEMP_ERROR_RETRY_DELAY: Duration = Duration::from_millis(50);
 const MAX_TEMP_ERROR_RETRIES: u32 = 3;
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+const DRAIN_LIMIT: usize = 1024 * 1024;
+const STREAM_WRITE_BUFFER_SIZE: usize = 8192;
+const METRICS_PATHS: [&str; 1] = ["/stats"];
 
 type RouteHandler = Arc<dyn Fn(&Request, &ServerState) -> Response + Send + Sync>;
+type RouteTable = router::RouteTable<RouteHandler>;
+type StatusInterceptor = Box<dyn Fn(&Request, &mut Response) + Send + Sync>;
+type ContentTypeAllowlist = HashMap<(Method, String), Vec<String>>;
+type NotFoundHandler = Box<dyn Fn(&Request) -> Response + Send + Sync>;
+type ErrorMapper = Box<dyn Fn(&HandlerError) -> Response + Send + Sync>;
 
 pub struct ServerState {
     start_time: chrono::DateTime<Utc>,
-    request_count: AtomicUsize,
-    error_count: AtomicUsize,
-    routes: Arc<RwLock<HashMap<(Method, String), RouteHandler>>>,
+    metrics: Box<dyn MetricsSink>,
+    not_found_handler: Option<NotFoundHandler>,
+    error_mapper: Option<ErrorMapper>,
+    health: HealthRegistry,
+    routes: Arc<RwLock<RouteTable>>,
+    accepted_content_types: ContentTypeAllowlist,
+    // Run in registration order on responses whose status falls in the range
+    status_interceptors: Vec<(RangeInclusive<u16>, StatusInterceptor)>,
+    // Built-in routes that take no body, e.g. POST /admin/drain, so a bare POST is not held
+    // to require_length or require_content_type
+    bodiless_routes: HashSet<(Method, String)>,
+    // GET routes whose concurrent identical requests share one handler run
+    coalesced_routes: HashSet<String>,
+    single_flight: SingleFlight,
+    // Shared with every request through its extensions for Request::parse_body
+    body_parsers: Arc<BodyParsers>,
+    // Fallback for paths no route handles, when static_dir is set
+    static_files: Option<StaticFiles>,
+    // Path prefix to upstream, checked after exact routes
+    proxies: Vec<(String, ProxyHandler)>,
+    // Heuristics for the recovery pause only; no other data is published through them
     consecutive_errors: AtomicUsize,
     last_error_time: RwLock<chrono::DateTime<Utc>>,
+    recovery_backoff_level: AtomicUsize,
+    // Set while a recovery pause is being served as 503s instead of sleeping
+    recovering_until: Mutex<Option<Instant>>,
+    // Recent access log entries for GET /admin/logs, when access_log_buffer is configured
+    access_log: Option<Arc<AccessLogBuffer>>,
+    // Set by POST /admin/drain: /readyz reports 503 so a load balancer moves traffic away,
+    // while requests that still arrive are served normally
+    draining: AtomicBool,
+    // Cross-thread stop signal: set with AcqRel by shutdown, read with Acquire everywhere
+    is_shutting_down: AtomicUsize,
+    in_flight_per_ip: Mutex<HashMap<IpAddr, usize>>,
+    open_connections_per_ip: Mutex<HashMap<IpAddr, usize>>,
+    config: Config,
+}
+
+// Releases a per-IP in-flight slot when the request finishes, even on early return
+struct InFlightGuard<'a> {
+    state: &'a ServerState,
+    ip: IpAddr,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.state.in_flight_per_ip.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                in_flight.remove(&self.ip);
+            }
+        }
+    }
+}
+
+// Counts an accepted connection against its IP until the job handling it is done or dropped
+struct ConnectionGuard {
+    state: Arc<ServerState>,
+    ip: IpAddr,
+}
+
+impl ConnectionGuard {
+    fn acquire(state: &Arc<ServerState>, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut open = state.open_connections_per_ip.lock().unwrap();
+        let count = open.get(&ip).copied().unwrap_or(0);
+        if state.config.max_connections_per_ip.is_some_and(|limit| count >= limit) {
+            return None;
+        }
+        open.insert(ip, count + 1);
+        Some(ConnectionGuard { state: Arc::clone(state), ip })
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.state.open_connections_per_ip.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl ServerState {
+    fn shutting_down(&self) -> bool {
+        self.is_shutting_down.load(Ordering::Acquire) > 0
+    }
+
+    // Each recovery pause in a row doubles the previous one, up to MAX_RECOVERY_BACKOFF
+    fn next_recovery_backoff(&self) -> Duration {
+        let level = self.recovery_backoff_level.fetch_add(1, Ordering::Relaxed);
+        2u32.checked_pow(level as u32)
+            .and_then(|factor| INITIAL_RECOVERY_BACKOFF.checked_mul(factor))
+            .map_or(MAX_RECOVERY_BACKOFF, |backoff| backoff.min(MAX_RECOVERY_BACKOFF))
+    }
+
+    fn recovery_remaining(&self) -> Option<Duration> {
+        let until = (*self.recovering_until.lock().unwrap())?;
+        until.checked_duration_since(Instant::now()).filter(|d| !d.is_zero())
+    }
+
+    fn try_acquire_slot(&self, ip: IpAddr) -> Option<InFlightGuard<'_>> {
+        let mut in_flight = self.in_flight_per_ip.lock().unwrap();
+        let count = in_flight.get(&ip).copied().unwrap_or(0);
+        if self.config.max_concurrent_per_ip.is_some_and(|limit| count >= limit) {
+            return None;
+        }
+        in_flight.insert(ip, count + 1);
+        Some(InFlightGuard { state: self, ip })
+    }
+
+    // Longest matching prefix wins; "/api" covers "/api" and "/api/..." but not "/apix"
+    fn proxy_for(&self, path: &str) -> Option<&ProxyHandler> {
+        self.proxies.iter()
+            .filter(|(prefix, _)| match path.strip_prefix(prefix.as_str()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+                None => false,
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, proxy)| proxy)
+    }
+
+    fn error_response(&self, error: &HandlerError) -> Response {
+        match &self.error_mapper {
+            Some(mapper) => mapper(error),
+            None => error.to_response(),
+        }
+    }
+}
+
+// Errors a fallible route handler can bail out with via `?`
+#[derive(Debug)]
+pub enum HandlerError {
+    BadRequest(String),
+    NotFound,
+    Internal(String),
+}
+
+impl HandlerError {
+    // Default mapping; internal details are logged rather than sent to the client
+    pub fn to_response(&self) -> Response {
+        match self {
+            HandlerError::BadRequest(message) => Response::bad_request(message),
+            HandlerError::NotFound => Response::not_found(),
+            HandlerError::Internal(message) => {
+                error!("Handler error: {}", message);
+                Response::internal_server_error()
+            }
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::BadRequest(message) => write!(f, "Bad request: {}", message),
+            HandlerError::NotFound => write!(f, "Not found"),
+            HandlerError::Internal(message) => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+impl From<ParseError> for HandlerError {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::IoError(e) => HandlerError::Internal(e.to_string()),
+            _ => HandlerError::BadRequest("Invalid request body".to_string()),
+        }
+    }
+}
+
+impl From<BodyError> for HandlerError {
+    fn from(error: BodyError) -> Self {
+        match error {
+            BodyError::Read(e) => e.into(),
+            e => HandlerError::BadRequest(e.to_string()),
+        }
+    }
+}
+
+impl From<io::Error> for HandlerError {
+    fn from(error: io::Error) -> Self {
+        HandlerError::Internal(error.to_string())
+    }
+}
+
+impl From<serde_json::Error> for HandlerError {
+    fn from(error: serde_json::Error) -> Self {
+        HandlerError::BadRequest(error.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AcceptFailure {
+    // The connection went away before it was accepted, or the call was interrupted
+    Transient,
+    // EMFILE, ENFILE, ENOBUFS, ENOMEM: existing connections need to finish first
+    ResourcePressure,
+    // The listener itself is unusable (EBADF, EINVAL, ENOTSOCK)
+    Fatal,
+    Other,
+}
+
+impl AcceptFailure {
+    fn classify(error: &io::Error) -> AcceptFailure {
+        match error.kind() {
+            ErrorKind::WouldBlock | ErrorKind::Interrupted | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset => {
+                return AcceptFailure::Transient;
+            }
+            ErrorKind::OutOfMemory => return AcceptFailure::ResourcePressure,
+            ErrorKind::InvalidInput => return AcceptFailure::Fatal,
+            _ => {}
+        }
+        // Linux errno values; elsewhere these fall through to Other
+        match error.raw_os_error() {
+            Some(9 | 88) => AcceptFailure::Fatal,
+            Some(12 | 23 | 24 | 105) => AcceptFailure::ResourcePressure,
+            _ => AcceptFailure::Other,
+        }
+    }
 }
 
 pub struct Server {
@@ -34,7 +280,29 @@ pub struct Server {
     pool: ThreadPool,
     middleware: Arc<Vec<Box<dyn Middleware>>>,
     state: Arc<ServerState>,
-    is_shutting_down: Arc<AtomicUsize>,
+}
+
+#[derive(Debug)]
+pub struct ShutdownReport {
+    pub drain_duration: Duration,
+    pub in_flight_at_start: usize,
+    pub completed: usize,
+    pub abandoned: usize,
+    pub timed_out: bool,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "drained in {}ms: {} in flight, {} completed, {} abandoned{}",
+            self.drain_duration.as_millis(),
+            self.in_flight_at_start,
+            self.completed,
+            self.abandoned,
+            if self.timed_out { " (grace timeout hit)" } else { "" }
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -71,18 +339,41 @@ impl From<ThreadPoolError> for ServerError {
 }
 
 impl Server {
-    pub fn new(addr: &str, workers: usize) -> Result<Self, ServerError> {
-        info!("Initializing server on {} with {} worker threads", addr, workers);
-        let listener = TcpListener::bind(addr)?;
-        let pool = ThreadPool::new(workers)?;
+    pub fn new(config: &Config) -> Result<Self, ServerError> {
+        let addr = config.address();
+        info!("Initializing server on {} with {} worker threads", addr, config.workers);
+        let listener = TcpListener::bind(&addr)?;
+        let pool = ThreadPool::with_options(config.workers, PoolOptions {
+            max_jobs: config.worker_max_jobs,
+            queue_capacity: config.queue_capacity,
+            rejection_policy: config.rejection_policy,
+        })?;
         
         let state = Arc::new(ServerState {
             start_time: Utc::now(),
-            request_count: AtomicUsize::new(0),
-            error_count: AtomicUsize::new(0),
-            routes: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Box::new(InMemoryMetrics::default()),
+            not_found_handler: None,
+            error_mapper: None,
+            health: HealthRegistry::default(),
+            routes: Arc::new(RwLock::new(RouteTable::default())),
+            accepted_content_types: HashMap::new(),
+            body_parsers: Arc::new(BodyParsers::default()),
+            status_interceptors: Vec::new(),
+            bodiless_routes: Server::bodiless_routes(config),
+            coalesced_routes: HashSet::new(),
+            single_flight: SingleFlight::default(),
+            proxies: Server::build_proxies(config),
+            static_files: Server::build_static_files(config),
             consecutive_errors: AtomicUsize::new(0),
             last_error_time: RwLock::new(Utc::now()),
+            recovery_backoff_level: AtomicUsize::new(0),
+            recovering_until: Mutex::new(None),
+            draining: AtomicBool::new(false),
+            is_shutting_down: AtomicUsize::new(0),
+            access_log: config.access_log_buffer.map(|capacity| Arc::new(AccessLogBuffer::new(capacity))),
+            in_flight_per_ip: Mutex::new(HashMap::new()),
+            open_connections_per_ip: Mutex::new(HashMap::new()),
+            config: config.clone(),
         });
 
         // Register routes
@@ -93,161 +384,522 @@ impl Server {
             pool,
             middleware: Arc::new(Vec::new()),
             state,
-            is_shutting_down: Arc::new(AtomicUsize::new(0)),
         })
     }
 
+    fn bodiless_routes(config: &Config) -> HashSet<(Method, String)> {
+        let mut routes = HashSet::new();
+        if config.admin_token.is_some() {
+            routes.insert((Method::POST, "/admin/drain".to_string()));
+        }
+        routes
+    }
+
+    fn build_proxies(config: &Config) -> Vec<(String, ProxyHandler)> {
+        let mut proxies = Vec::new();
+        for (prefix, url) in &config.proxies {
+            match Upstream::parse(url) {
+                Ok(upstream) => {
+                    info!("Proxying {} to {}", prefix, url);
+                    let limits = ParseLimits {
+                        max_body_size: config.max_body_size,
+                        max_chunk_size: config.max_chunk_size,
+                        spool_threshold: config.upload_spool_threshold,
+                    };
+                    proxies.push((prefix.trim_end_matches('/').to_string(), ProxyHandler::new(upstream, limits, config.upstream_pool.clone())));
+                }
+                Err(e) => error!("Ignoring proxy for {}: {}", prefix, e),
+            }
+        }
+        proxies
+    }
+
+    fn build_static_files(config: &Config) -> Option<StaticFiles> {
+        let dir = config.static_dir.as_deref()?;
+        match StaticFiles::new(dir) {
+            Ok(files) => {
+                info!("Serving static files from {}", dir);
+                Some(files)
+            }
+            Err(e) => {
+                error!("Not serving static files from {}: {}", dir, e);
+                None
+            }
+        }
+    }
+
     fn register_default_routes(state: &ServerState) {
         let mut routes = state.routes.write().unwrap();
         
         // Home page
-        routes.insert(
-            (Method::GET, "/".to_string()),
-            Arc::new(|_req, state| {
-                Response::ok("text/html", Server::render_home_page(state))
-            })
-        );
+        let home: RouteHandler = Arc::new(|_req, state| {
+            Response::ok("text/html", Server::render_home_page(state))
+        });
+        routes.insert((Method::GET, "/".to_string()), Arc::clone(&home));
+        routes.insert((Method::HEAD, "/".to_string()), home);
 
         // Health check
-        routes.insert(
-            (Method::GET, "/health".to_string()),
-            Arc::new(|_req, _state| {
-                Response::ok("text/plain", b"Server is healthy!".to_vec())
-            })
-        );
+        let health: RouteHandler = Arc::new(|_req, state| {
+            let report = state.health.report();
+            let body = report.body.to_string().into_bytes();
+            let mut response = if report.healthy {
+                Response::ok("application/json", body)
+            } else {
+                Response::new(503, "Service Unavailable", "application/json", body)
+            };
+            response.headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+            response
+        });
+        routes.insert((Method::GET, "/health".to_string()), Arc::clone(&health));
+        routes.insert((Method::HEAD, "/health".to_string()), health);
 
-        // Server stats
-        routes.insert(
-            (Method::GET, "/stats".to_string()),
-            Arc::new(|_req, state| {
-                let mut response = Response::ok("application/json", 
-                    Server::get_server_stats(state).into_bytes());
+        // Readiness: not ready until the warmup period after start has passed
+        let ready: RouteHandler = Arc::new(|_req, state| {
+            if state.draining.load(Ordering::Acquire) {
+                let body = json!({ "status": "draining" }).to_string().into_bytes();
+                let mut response = Response::new(503, "Service Unavailable", "application/json", body);
                 response.headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+                return response;
+            }
+            let warmup = chrono::Duration::seconds(state.config.warmup_seconds as i64);
+            let remaining = (state.start_time + warmup).signed_duration_since(Utc::now());
+            let mut response = match remaining.to_std() {
+                Ok(remaining) if !remaining.is_zero() => {
+                    let body = json!({ "status": "warming_up", "remaining_seconds": remaining.as_secs_f64().ceil() as u64 });
+                    Response::new(503, "Service Unavailable", "application/json", body.to_string().into_bytes())
+                        .with_retry_after(RetryAfter::after(remaining))
+                }
+                _ => Response::ok("application/json", json!({ "status": "ready" }).to_string().into_bytes()),
+            };
+            response.headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+            response
+        });
+        routes.insert((Method::GET, "/readyz".to_string()), Arc::clone(&ready));
+        routes.insert((Method::HEAD, "/readyz".to_string()), ready);
+
+        // Server stats
+        let stats: RouteHandler = Arc::new(|_req, state| {
+            let mut response = Response::ok("application/json", 
+                Server::get_server_stats(state).into_bytes());
+            response.headers.insert("Cache-Control".to_string(), "no-cache".to_string());
+            response
+        });
+        routes.insert((Method::GET, "/stats".to_string()), Arc::clone(&stats));
+        routes.insert((Method::HEAD, "/stats".to_string()), stats);
+
+        // Admin endpoints exist only when a token is configured to guard them
+        if state.config.admin_token.is_some() {
+            routes.insert(
+                (Method::POST, "/admin/drain".to_string()),
+                Arc::new(|req, state| {
+                    if let Some(denied) = check_admin_auth(req, &state.config) {
+                        return denied;
+                    }
+                    if !state.draining.swap(true, Ordering::AcqRel) {
+                        info!("Draining: /readyz now reports 503, requests are still served");
+                    }
+                    let body = json!({ "status": "draining" }).to_string().into_bytes();
+                    Response::new(202, "Accepted", "application/json", body)
+                }),
+            );
+
+            let logs: RouteHandler = Arc::new(|req, state| {
+                if let Some(denied) = check_admin_auth(req, &state.config) {
+                    return denied;
+                }
+                let entries = state.access_log.as_ref().map_or_else(Vec::new, |buffer| buffer.snapshot());
+                let mut response = Response::ok("application/json", json!({ "entries": entries }).to_string().into_bytes());
+                response.headers.insert("Cache-Control".to_string(), "no-store".to_string());
                 response
-            })
-        );
+            });
+            routes.insert((Method::GET, "/admin/logs".to_string()), Arc::clone(&logs));
+            routes.insert((Method::HEAD, "/admin/logs".to_string()), logs);
+        }
+
+        // Crawler files, only when configured
+        let crawler_files = [
+            ("/robots.txt", "text/plain; charset=utf-8", &state.config.robots_txt),
+            ("/sitemap.xml", "application/xml", &state.config.sitemap_xml),
+        ];
+        for (path, content_type, source) in crawler_files {
+            let Some(source) = source else { continue };
+            let body = match load_inline_or_file(source) {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Not serving {}: {}", path, e);
+                    continue;
+                }
+            };
+            let handler: RouteHandler = Arc::new(move |_req, _state| Response::ok(content_type, body.clone()));
+            routes.insert((Method::GET, path.to_string()), Arc::clone(&handler));
+            routes.insert((Method::HEAD, path.to_string()), handler);
+        }
 
         // Echo server
         routes.insert(
             (Method::POST, "/echo".to_string()),
             Arc::new(|req, _state| {
-                Response::ok("text/plain", req.body.clone())
+                match req.load_body() {
+                    Ok(body) => Response::ok("text/plain", body),
+                    Err(_) => Response::bad_request("Invalid request body"),
+                }
             })
         );
     }
 
+    pub fn with_metrics(mut self, metrics: Box<dyn MetricsSink>) -> Self {
+        Arc::get_mut(&mut self.state)
+            .expect("metrics sink must be set before the server is shared")
+            .metrics = metrics;
+        self
+    }
+
+    // Replaces the built-in 404 page for requests that match no route
+    pub fn with_not_found_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.state)
+            .expect("not-found handler must be set before the server is shared")
+            .not_found_handler = Some(Box::new(handler));
+        self
+    }
+
+    // Adds or replaces the handler for an exact method and path. The route table has its own
+    // lock, so this stays safe while requests are being served.
+    pub fn route<F>(self, method: Method, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, &ServerState) -> Response + Send + Sync + 'static,
+    {
+        self.state.routes.write().unwrap().insert((method, path.to_string()), Arc::new(handler));
+        self
+    }
+
+    // Registers a handler that can return a HandlerError; errors go through the error mapper
+    pub fn with_fallible_route<F>(self, method: Method, path: &str, handler: F) -> Self
+    where
+        F: Fn(&Request, &ServerState) -> Result<Response, HandlerError> + Send + Sync + 'static,
+    {
+        self.route(method, path, move |req, state| {
+            handler(req, state).unwrap_or_else(|e| state.error_response(&e))
+        })
+    }
+
+    // Adds a named dependency check to the /health report
+    pub fn with_health_check<F>(mut self, name: &str, critical: bool, check: F) -> Self
+    where
+        F: Fn() -> HealthStatus + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.state)
+            .expect("health checks must be registered before the server is shared")
+            .health
+            .register(name, critical, check);
+        self
+    }
+
+    // Restricts a route to request bodies of the given media types; others get 415
+    pub fn with_accepted_content_types(mut self, method: Method, path: &str, media_types: &[&str]) -> Self {
+        Arc::get_mut(&mut self.state)
+            .expect("content type allowlists must be set before the server is shared")
+            .accepted_content_types
+            .insert((method, path.to_string()), media_types.iter().map(|t| t.to_string()).collect());
+        self
+    }
+
+    // Adds or replaces the parser Request::parse_body uses for a media type
+    pub fn with_body_parser<F>(mut self, media_type: &str, parser: F) -> Self
+    where
+        F: Fn(&[u8]) -> Result<Value, String> + Send + Sync + 'static,
+    {
+        let state = Arc::get_mut(&mut self.state)
+            .expect("body parsers must be registered before the server is shared");
+        Arc::get_mut(&mut state.body_parsers)
+            .expect("body parsers must be registered before the server is shared")
+            .register(media_type, parser);
+        self
+    }
+
+    // Runs `interceptor` on every response with this status, after the handler and before the
+    // middleware after() hooks, so e.g. the access log sees headers it adds
+    pub fn with_status_interceptor<F>(self, status: u16, interceptor: F) -> Self
+    where
+        F: Fn(&Request, &mut Response) + Send + Sync + 'static,
+    {
+        self.with_status_range_interceptor(status..=status, interceptor)
+    }
+
+    pub fn with_status_range_interceptor<F>(mut self, statuses: RangeInclusive<u16>, interceptor: F) -> Self
+    where
+        F: Fn(&Request, &mut Response) + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.state)
+            .expect("status interceptors must be registered before the server is shared")
+            .status_interceptors
+            .push((statuses, Box::new(interceptor)));
+        self
+    }
+
+    // Identical concurrent GETs of this route (same path and query) wait for the first one's
+    // response instead of running the handler again. Streamed responses are not shared.
+    pub fn with_coalesced_route(mut self, path: &str) -> Self {
+        Arc::get_mut(&mut self.state)
+            .expect("coalesced routes must be set before the server is shared")
+            .coalesced_routes
+            .insert(path.to_string());
+        self
+    }
+
+    // Replaces the default HandlerError to response mapping
+    pub fn with_error_mapper<F>(mut self, mapper: F) -> Self
+    where
+        F: Fn(&HandlerError) -> Response + Send + Sync + 'static,
+    {
+        Arc::get_mut(&mut self.state)
+            .expect("error mapper must be set before the server is shared")
+            .error_mapper = Some(Box::new(mapper));
+        self
+    }
+
+    // The buffer behind GET /admin/logs, for the access log middleware to fill
+    pub fn access_log(&self) -> Option<Arc<AccessLogBuffer>> {
+        self.state.access_log.clone()
+    }
+
+    // Middleware is kept sorted by priority (lowest first); equal priorities keep registration order
     pub fn with_middleware(mut self, middleware: Box<dyn Middleware>) -> Self {
         let mut m = Vec::new();
         std::mem::swap(&mut m, Arc::get_mut(&mut self.middleware).unwrap());
         m.push(middleware);
+        m.sort_by_key(|m| m.priority());
         self.middleware = Arc::new(m);
         self
     }
 
+    pub fn middleware_order(&self) -> Vec<(&'static str, i32)> {
+        self.middleware.iter().map(|m| (m.name(), m.priority())).collect()
+    }
+
     pub fn run(&self) -> Result<(), ServerError> {
         info!("Server listening on {}", self.listener.local_addr()?);
         info!("Active worker threads: {}", self.pool.active_count());
+        debug!("Middleware order: {:?}", self.middleware_order());
 
-        while self.is_shutting_down.load(Ordering::Relaxed) == 0 {
+        while !self.state.shutting_down() {
             if self.state.consecutive_errors.load(Ordering::Relaxed) >= MAX_CONSECUTIVE_ERRORS {
                 let last_error = *self.state.last_error_time.read().unwrap();
                 let elapsed = Utc::now().signed_duration_since(last_error);
                 
                 if elapsed < chrono::Duration::from_std(ERROR_RECOVERY_INTERVAL).unwrap() {
-                    error!("Too many consecutive errors, pausing for recovery");
-                    std::thread::sleep(ERROR_RECOVERY_INTERVAL);
+                    let backoff = self.state.next_recovery_backoff();
                     self.state.consecutive_errors.store(0, Ordering::Relaxed);
-                    return Err(ServerError::TooManyErrors);
+                    if self.state.config.reject_during_recovery {
+                        error!("Too many consecutive errors, answering 503 for {}s while recovering", backoff.as_secs());
+                        *self.state.recovering_until.lock().unwrap() = Some(Instant::now() + backoff);
+                    } else {
+                        error!("Too many consecutive errors, pausing {}s for recovery", backoff.as_secs());
+                        std::thread::sleep(backoff);
+                        continue;
+                    }
                 }
             }
 
-            if self.is_shutting_down.load(Ordering::Relaxed) > 0 {
-                return Err(ServerError::ShuttingDown);
+            if self.state.shutting_down() {
+                break;
             }
 
             match self.listener.accept() {
+                // Includes the connection shutdown makes to wake this call
+                Ok(_) if self.state.shutting_down() => break,
                 Ok((stream, addr)) => {
                     self.state.consecutive_errors.store(0, Ordering::Relaxed);
-                    self.state.request_count.fetch_add(1, Ordering::Relaxed);
+                    self.state.recovery_backoff_level.store(0, Ordering::Relaxed);
+                    self.state.metrics.record_connection();
+
+                    // Refuse before spending a worker; dropping the stream closes it
+                    let Some(connection_guard) = ConnectionGuard::acquire(&self.state, addr.ip()) else {
+                        warn!("Too many open connections from {}, closing", addr.ip());
+                        continue;
+                    };
                     
                     let start_time = Utc::now();
                     debug!("New connection from {}", addr);
 
-                    // Configure stream
-                    if let Err(e) = stream.set_read_timeout(Some(MAX_REQUEST_TIMEOUT)) {
-                        error!("Failed to set read timeout: {}", e);
-                        continue;
+                    // Configure stream; None (or 0) leaves the socket blocking without a timeout
+                    let config = &self.state.config;
+                    let write_timeout = config.write_timeout_secs.filter(|s| *s > 0).map(Duration::from_secs);
+                    if let Err(e) = stream.set_read_timeout(config.read_timeout()) {
+                        warn!("Failed to set read timeout for {}, continuing without it: {}", addr, e);
                     }
-                    if let Err(e) = stream.set_write_timeout(Some(MAX_REQUEST_TIMEOUT)) {
-                        error!("Failed to set write timeout: {}", e);
+                    if let Err(e) = stream.set_write_timeout(write_timeout) {
+                        warn!("Failed to set write timeout for {}, continuing without it: {}", addr, e);
+                    }
+
+                    if self.state.config.shed_when_saturated && !self.pool.has_capacity() {
+                        warn!("Worker pool saturated ({} active, {} queued), rejecting {} with 503",
+                            self.pool.active_count(), self.pool.queued_count(), addr);
+                        let mut stream = stream;
+                        let response = Response::service_unavailable().with_retry_after(RetryAfter::Seconds(1));
+                        if let Err(e) = write_response(&mut stream, response) {
+                            debug!("Failed to send 503 to {}: {}", addr, e);
+                        }
                         continue;
                     }
 
                     let state = Arc::clone(&self.state);
-                    let is_shutting_down = Arc::clone(&self.is_shutting_down);
                     let middleware = Arc::clone(&self.middleware);
+                    // Keep a handle to answer 503 if the job never makes it into the queue
+                    let overflow_stream = if self.pool.rejects_when_full() {
+                        stream.try_clone().ok()
+                    } else {
+                        None
+                    };
+                    let enqueued_at = Instant::now();
 
-                    self.pool.execute(move || {
-                        if is_shutting_down.load(Ordering::Relaxed) > 0 {
+                    let queued = self.pool.execute(move || {
+                        let _connection_guard = connection_guard;
+                        state.metrics.record_queue_wait(enqueued_at.elapsed());
+                        // Queued before shutdown began: answer rather than drop the connection. The
+                        // drain is kept short since every queued connection holds up the shutdown.
+                        if state.shutting_down() {
+                            let mut stream = stream;
+                            let response = Response::service_unavailable();
+                            if let Err(e) = reject_unread_body_within(&mut stream, response, SHUTDOWN_DRAIN_TIMEOUT) {
+                                debug!("Failed to send 503 to {}: {}", addr, e);
+                            }
                             return;
                         }
 
                         if let Err(e) = handle_connection(stream, &state, &middleware) {
                             error!("Error handling connection from {}: {}", addr, e);
-                            state.error_count.fetch_add(1, Ordering::Relaxed);
+                            state.metrics.record_error();
                             state.consecutive_errors.fetch_add(1, Ordering::Relaxed);
                             *state.last_error_time.write().unwrap() = Utc::now();
                         }
                         
                         let duration = Utc::now().signed_duration_since(start_time);
+                        state.metrics.record_duration(duration.num_milliseconds().max(0) as u64);
                         debug!("Request from {} completed in {}ms", addr, duration.num_milliseconds());
-                    })?;
-                }
-                Err(e) => {
-                    error!("Error accepting connection: {}", e);
-                    self.state.error_count.fetch_add(1, Ordering::Relaxed);
-                    self.state.consecutive_errors.fetch_add(1, Ordering::Relaxed);
-                    *self.state.last_error_time.write().unwrap() = Utc::now();
+                    });
+
+                    match queued {
+                        Ok(()) => {}
+                        Err(ThreadPoolError::QueueFull) => {
+                            warn!("Job queue full, rejecting connection from {} with 503", addr);
+                            if let Some(mut stream) = overflow_stream {
+                                if let Err(e) = write_response(&mut stream, Response::service_unavailable()) {
+                                    debug!("Failed to send 503 to {}: {}", addr, e);
+                                }
+                            }
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
                 }
+                Err(e) => match AcceptFailure::classify(&e) {
+                    AcceptFailure::Transient => debug!("Transient error accepting connection: {}", e),
+                    AcceptFailure::Fatal => {
+                        error!("Listener failed, stopping: {}", e);
+                        return Err(e.into());
+                    }
+                    failure => {
+                        error!("Error accepting connection: {}", e);
+                        self.state.metrics.record_error();
+                        self.state.consecutive_errors.fetch_add(1, Ordering::Relaxed);
+                        *self.state.last_error_time.write().unwrap() = Utc::now();
+                        // Out of descriptors or memory: accepting again right away would spin
+                        if failure == AcceptFailure::ResourcePressure {
+                            std::thread::sleep(Duration::from_millis(self.state.config.accept_error_backoff_ms));
+                        }
+                    }
+                },
             }
         }
         Ok(())
     }
 
-    pub fn shutdown(&self) -> Result<(), ServerError> {
+    pub fn shutdown(&self) -> Result<ShutdownReport, ServerError> {
+        // Only the first caller drains; a repeated Ctrl-C or a racing call gets ShuttingDown
+        if self.state.is_shutting_down.compare_exchange(0, 1, Ordering::AcqRel, Ordering::Acquire).is_err() {
+            debug!("Shutdown already in progress");
+            return Err(ServerError::ShuttingDown);
+        }
         info!("Shutting down server...");
-        self.is_shutting_down.store(1, Ordering::Relaxed);
-        Ok(())
+        self.wake_accept_loop();
+
+        // Workers finish the request they are on; keep-alive connections close after it, and
+        // connections still queued are answered with 503, so both count as in flight
+        let grace = Duration::from_secs(self.state.config.shutdown_timeout_secs);
+        let started = Instant::now();
+        let in_flight = || self.pool.active_count() + self.pool.queued_count();
+        let in_flight_at_start = in_flight();
+        let mut remaining = in_flight_at_start;
+        while remaining > 0 && started.elapsed() < grace {
+            std::thread::sleep(SHUTDOWN_POLL_INTERVAL);
+            remaining = in_flight();
+        }
+
+        Ok(ShutdownReport {
+            drain_duration: started.elapsed(),
+            in_flight_at_start,
+            completed: in_flight_at_start.saturating_sub(remaining),
+            abandoned: remaining,
+            timed_out: remaining > 0,
+        })
+    }
+
+    // accept() blocks until the next connection arrives, so make one for run() to see the flag
+    fn wake_accept_loop(&self) {
+        let Ok(mut addr) = self.listener.local_addr() else {
+            return;
+        };
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr.ip() {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        if let Err(e) = TcpStream::connect_timeout(&addr, Duration::from_secs(1)) {
+            debug!("Failed to wake the accept loop: {}", e);
+        }
     }
 
     fn render_home_page(state: &ServerState) -> Vec<u8> {
-        let html = format!(r#"<!DOCTYPE html>
+        let metrics_section = if state.config.expose_metrics_on_home {
+            Server::render_home_metrics(state)
+        } else {
+            String::new()
+        };
+        let context = HashMap::from([
+            ("base".to_string(), state.config.base_path.trim_end_matches('/').to_string()),
+            ("metrics_section".to_string(), metrics_section),
+            ("server_time".to_string(), Utc::now().format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+        ]);
+        let html = template::render(r#"<!DOCTYPE html>
     <html lang="en">
     <head>
         <meta charset="utf-8">
         <meta name="viewport" content="width=device-width, initial-scale=1">
         <title>Rust HTTP Server - Welcome</title>
         <style>
-            body {{
+            body {
                 font-family: 'Segoe UI', Tahoma, Geneva, Verdana, sans-serif;
                 margin: 0;
                 padding: 0;
                 background: linear-gradient(135deg, #ece9e6, #ffffff);
                 color: #333;
-            }}
-            .container {{
+            }
+            .container {
                 max-width: 1200px;
                 margin: 50px auto;
                 background: #fff;
                 padding: 40px;
                 border-radius: 12px;
                 box-shadow: 0 4px 12px rgba(0,0,0,0.1);
-            }}
-            header {{
+            }
+            header {
                 text-align: center;
                 margin-bottom: 30px;
-            }}
-            .logo {{
+            }
+            .logo {
                 width: 80px;
                 height: 80px;
                 background: #2980b9;
@@ -258,13 +910,13 @@ impl Server {
                 justify-content: center;
                 font-size: 2em;
                 color: #fff;
-            }}
-            header h1 {{
+            }
+            header h1 {
                 font-size: 3em;
                 margin: 0;
                 color: #2c3e50;
-            }}
-            .status {{
+            }
+            .status {
                 display: inline-block;
                 background: #27ae60;
                 color: #fff;
@@ -273,78 +925,78 @@ impl Server {
                 font-weight: bold;
                 margin-top: 10px;
                 animation: pulse 2s infinite;
-            }}
-            @keyframes pulse {{
-                0% {{ transform: scale(1); }}
-                50% {{ transform: scale(1.05); }}
-                100% {{ transform: scale(1); }}
-            }}
-            nav {{
+            }
+            @keyframes pulse {
+                0% { transform: scale(1); }
+                50% { transform: scale(1.05); }
+                100% { transform: scale(1); }
+            }
+            nav {
                 background: #f8f9fa;
                 padding: 20px;
                 border-radius: 8px;
                 margin: 30px 0;
                 border: 1px solid #dee2e6;
-            }}
-            nav ul {{
+            }
+            nav ul {
                 list-style: none;
                 padding: 0;
                 display: flex;
                 flex-wrap: wrap;
                 justify-content: center;
-            }}
-            nav li {{
+            }
+            nav li {
                 margin: 10px 15px;
-            }}
-            nav a {{
+            }
+            nav a {
                 color: #3498db;
                 text-decoration: none;
                 font-weight: 500;
                 transition: color 0.2s;
-            }}
-            nav a:hover {{
+            }
+            nav a:hover {
                 color: #2980b9;
-            }}
-            .stats {{
+            }
+            .stats {
                 background: #e9ecef;
                 padding: 30px;
                 border-radius: 8px;
                 border: 1px solid #dee2e6;
                 margin-bottom: 30px;
-            }}
-            .stats h2 {{
+            }
+            .stats h2 {
                 text-align: center;
                 color: #34495e;
                 margin-bottom: 20px;
-            }}
-            .metrics {{
+            }
+            .metrics {
                 display: grid;
                 grid-template-columns: repeat(auto-fit, minmax(200px, 1fr));
                 gap: 20px;
-            }}
-            .metric-card {{
+            }
+            .metric-card {
                 background: #fff;
                 padding: 20px;
                 border-radius: 8px;
                 text-align: center;
                 box-shadow: 0 2px 6px rgba(0,0,0,0.1);
-            }}
-            .metric-value {{
+            }
+            .metric-value {
                 font-size: 2em;
                 font-weight: bold;
                 color: #2980b9;
-            }}
-            .metric-label {{
+            }
+            .metric-label {
                 font-size: 0.9em;
                 color: #7f8c8d;
                 margin-top: 5px;
-            }}
-            footer {{
+            }
+            footer {
                 text-align: center;
                 font-size: 0.9em;
                 color: #7f8c8d;
                 margin-top: 40px;
-            }}
+            }
         </style>
     </head>
     <body>
@@ -357,48 +1009,53 @@ impl Server {
             <nav>
                 <h3>Available Routes</h3>
                 <ul>
-                    <li><a href="/">Home</a></li>
-                    <li><a href="/health">Health Check</a></li>
-                    <li><a href="/stats">Server Statistics (JSON)</a></li>
-                    <li><a href="/echo">Echo Service (POST)</a></li>
+                    <li><a href="{{ base }}/">Home</a></li>
+                    <li><a href="{{ base }}/health">Health Check</a></li>
+                    <li><a href="{{ base }}/stats">Server Statistics (JSON)</a></li>
+                    <li><a href="{{ base }}/echo">Echo Service (POST)</a></li>
                 </ul>
             </nav>
-            <section class="stats">
+            {{{ metrics_section }}}
+            <footer>
+                <p>Powered by Rust 🦀 | Server Time: {{ server_time }}</p>
+            </footer>
+        </div>
+    </body>
+    </html>"#, &context);
+        html.into_bytes()
+    }
+
+    fn render_home_metrics(state: &ServerState) -> String {
+        let metrics = state.metrics.snapshot();
+        let context = HashMap::from([
+            ("requests".to_string(), metrics.requests.to_string()),
+            ("success_rate".to_string(), format!("{:.1}", success_rate(metrics.requests, metrics.errors))),
+            ("uptime_seconds".to_string(), Utc::now().signed_duration_since(state.start_time).num_seconds().to_string()),
+        ]);
+        template::render(r#"<section class="stats">
                 <h2>Server Metrics</h2>
                 <div class="metrics">
                     <div class="metric-card">
-                        <div class="metric-value">{}</div>
+                        <div class="metric-value">{{ requests }}</div>
                         <div class="metric-label">Total Requests</div>
                     </div>
                     <div class="metric-card">
-                        <div class="metric-value">{:.1}%</div>
+                        <div class="metric-value">{{ success_rate }}%</div>
                         <div class="metric-label">Success Rate</div>
                     </div>
                     <div class="metric-card">
-                        <div class="metric-value">{}</div>
+                        <div class="metric-value">{{ uptime_seconds }}</div>
                         <div class="metric-label">Uptime (seconds)</div>
                     </div>
                 </div>
-            </section>
-            <footer>
-                <p>Powered by Rust 🦀 | Server Time: {}</p>
-            </footer>
-        </div>
-    </body>
-    </html>"#,
-            state.request_count.load(Ordering::Relaxed),
-            100.0 - (100.0 * state.error_count.load(Ordering::Relaxed) as f64 
-                    / state.request_count.load(Ordering::Relaxed).max(1) as f64),
-            Utc::now().signed_duration_since(state.start_time).num_seconds(),
-            Utc::now().format("%Y-%m-%d %H:%M:%S UTC")
-        );
-        html.into_bytes()
+            </section>"#, &context)
     }
 
     fn get_server_stats(state: &ServerState) -> String {
         let uptime = Utc::now().signed_duration_since(state.start_time);
-        let total_requests = state.request_count.load(Ordering::Relaxed);
-        let error_count = state.error_count.load(Ordering::Relaxed);
+        let metrics = state.metrics.snapshot();
+        let total_requests = metrics.requests;
+        let error_count = metrics.errors;
         let routes: Vec<String> = state.routes.read().unwrap()
             .keys()
             .map(|(method, path)| format!("{:?} {}", method, path))
@@ -409,49 +1066,191 @@ impl Server {
             "uptime_seconds": uptime.num_seconds(),
             "start_time": state.start_time.to_rfc3339(),
             "total_requests": total_requests,
+            "total_connections": metrics.connections,
+            "requests_per_connection": format!("{:.2}", metrics.requests_per_connection()),
             "error_count": error_count,
-            "success_rate": format!("{:.2}%", 
-                if total_requests > 0 {
-                    100.0 * (1.0 - error_count as f64 / total_requests as f64)
-                } else {
-                    100.0
-                }
-            ),
+            "success_rate": format!("{:.2}%", success_rate(total_requests, error_count)),
+            "average_duration_ms": format!("{:.2}", metrics.average_duration_ms()),
+            "average_queue_wait_ms": format!("{:.3}", metrics.average_queue_wait_ms()),
+            "max_queue_wait_ms": format!("{:.3}", metrics.max_queue_wait_us as f64 / 1000.0),
             "consecutive_errors": state.consecutive_errors.load(Ordering::Relaxed),
             "available_routes": routes,
         }).to_string()
     }
 }
 
+// A value naming an existing file is read once at startup; anything else is the content itself
+fn load_inline_or_file(value: &str) -> io::Result<Vec<u8>> {
+    if Path::new(value).is_file() {
+        fs::read(value)
+    } else {
+        Ok(value.as_bytes().to_vec())
+    }
+}
+
 fn handle_connection(mut stream: TcpStream, state: &ServerState, middleware: &[Box<dyn Middleware>]) -> io::Result<()> {
     let peer_addr = stream.peer_addr()?;
+    let mut served = 0;
+    loop {
+        if served > 0 && !wait_for_next_request(&stream, &state.config)? {
+            debug!("Closing idle connection from {} after {} requests", peer_addr, served);
+            return Ok(());
+        }
+        served += 1;
+        if !handle_request(&mut stream, peer_addr, served, state, middleware)? {
+            return Ok(());
+        }
+    }
+}
+
+// Waits up to the keep-alive timeout for the next request to start. False when the client
+// closed the connection or stayed idle, which ends the connection without a response.
+fn wait_for_next_request(stream: &TcpStream, config: &Config) -> io::Result<bool> {
+    stream.set_read_timeout(Some(Duration::from_secs(config.keep_alive_timeout_secs)))?;
+    let mut first_byte = [0; 1];
+    let ready = match stream.peek(&mut first_byte) {
+        Ok(n) => n > 0,
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionReset) => false,
+        Err(e) => return Err(e),
+    };
+    stream.set_read_timeout(config.read_timeout())?;
+    Ok(ready)
+}
+
+// Handles one request on the connection; the result says whether the connection stays open
+fn handle_request(
+    stream: &mut TcpStream,
+    peer_addr: SocketAddr,
+    served: usize,
+    state: &ServerState,
+    middleware: &[Box<dyn Middleware>],
+) -> io::Result<bool> {
     trace!("Starting request handling for {}", peer_addr);
+
+    // Circuit breaker: shed load until the recovery pause is over
+    if let Some(remaining) = state.recovery_remaining() {
+        debug!("Rejecting {} during error recovery", peer_addr);
+        let response = Response::service_unavailable().with_retry_after(RetryAfter::after(remaining));
+        reject_unread_body(stream, response)?;
+        return Ok(false);
+    }
     
-    // Parse the request
-    let mut request = match Request::parse(&mut stream) {
-        Ok(request) => {
+    // Parse the head first so an Expect: 100-continue body can be declined before it is sent
+    let parsed = match Request::parse_head_limited(&mut *stream, state.config.max_query_params) {
+        Ok(mut request) => {
+            request.remote_addr = Some(peer_addr);
+            request.path = state.config.strip_base_path(&request.path).to_string();
+            // Count every request whose head parsed, including ones declined before the body
+            state.metrics.record_request();
+            // Rewrites go first so every check below sees the path the request will be routed by;
+            // as with process(), the first hook to answer stops the rest
+            let answered = middleware.iter().any(|m| m.rewrite(&mut request) == Rewrite::Answer);
+            let limits = ParseLimits {
+                max_body_size: state.config.max_body_size_for(&request.path),
+                max_chunk_size: state.config.max_chunk_size,
+                spool_threshold: state.config.upload_spool_threshold,
+            };
+
+            // The entry count was already capped by parse_head_limited
+            if let Some(query) = request.raw_query().filter(|q| q.len() > state.config.max_query_length) {
+                warn!("Query string too long from {}: {} bytes", peer_addr, query.len());
+                reject_unread_body(stream, Response::uri_too_long())?;
+                return Ok(false);
+            }
+
+            let bodiless = state.bodiless_routes.contains(&(request.method.clone(), request.path.clone()));
+            if let Some(response) = validate_body_policy(&request, &state.config.body_policy, bodiless).filter(|_| !answered) {
+                warn!("Rejected {:?} {} from {} by body policy: {}",
+                    request.method, request.path, peer_addr, response.status_code);
+                reject_unread_body(stream, response)?;
+                return Ok(false);
+            }
+
+            if !answered && !content_type_accepted(&request, &state.accepted_content_types) {
+                warn!("Unsupported Content-Type {:?} for {:?} {} from {}",
+                    request.header("Content-Type"), request.method, request.path, peer_addr);
+                reject_unread_body(stream, Response::unsupported_media_type())?;
+                return Ok(false);
+            }
+
+            if request.expects_continue() {
+                if let Some(response) = decline_continue(&request, state, &limits).filter(|_| !answered) {
+                    info!("Declining 100-continue for {:?} {} from {} with {}",
+                        request.method, request.path, peer_addr, response.status_code);
+                    reject_unread_body(stream, response)?;
+                    return Ok(false);
+                }
+                let head = Response::informational(100, "Continue").head_bytes();
+                write_response_with_retry(stream, &head, &[])?;
+            }
+            if state.config.lazy_body {
+                request.defer_body(stream.try_clone()?, limits.clone());
+                Ok(request)
+            } else {
+                request.read_body(&mut *stream, &limits).map(|_| request)
+            }
+        }
+        Err(e) => Err(e),
+    };
+
+    let mut request = match parsed {
+        Ok(mut request) => {
             info!("Received {:?} request for {} from {} with {} headers", 
                 request.method, request.path, peer_addr, request.headers.len());
-            
-            if request.method == Method::POST && !request.headers.contains_key("Content-Type") {
-                warn!("Missing Content-Type header for POST request from {}", peer_addr);
-                let response = Response::bad_request("Missing Content-Type header");
-                write_response_with_retry(&mut stream, &response.to_bytes())?;
-                return Ok(());
+
+            // Declining an h2c upgrade just means answering over HTTP/1.1 as usual
+            if request.headers.get("Upgrade").is_some_and(|v| v.to_lowercase().contains("h2c")) {
+                debug!("Declining h2c upgrade from {}", peer_addr);
             }
+            request.set_connection(stream.try_clone()?);
+            request.extensions.insert(Arc::clone(&state.body_parsers));
             request
         },
         Err(ParseError::ContentTooLarge) => {
             warn!("Request too large from {}", peer_addr);
-            let response = Response::bad_request("Request body too large");
-            write_response_with_retry(&mut stream, &response.to_bytes())?;
-            return Ok(());
+            let response = Response::payload_too_large();
+            reject_unread_body(stream, response)?;
+            return Ok(false);
         },
-        Err(ParseError::InvalidRequest) => {
-            warn!("Invalid request from {}", peer_addr);
-            let response = Response::bad_request("Invalid request format");
-            write_response_with_retry(&mut stream, &response.to_bytes())?;
-            return Ok(());
+        Err(ParseError::TooManyHeaders) => {
+            warn!("Too many request headers from {}", peer_addr);
+            let response = Response::request_header_fields_too_large();
+            write_response(stream, response)?;
+            return Ok(false);
+        },
+        Err(ParseError::UriTooLong) => {
+            warn!("Request line too long from {}", peer_addr);
+            reject_unread_body(stream, Response::uri_too_long())?;
+            return Ok(false);
+        },
+        Err(ParseError::TooManyQueryParams) => {
+            warn!("Too many query parameters from {}", peer_addr);
+            reject_unread_body(stream, Response::uri_too_long())?;
+            return Ok(false);
+        },
+        Err(ParseError::UnsupportedVersion) => {
+            warn!("Unsupported HTTP version from {}", peer_addr);
+            let response = Response::http_version_not_supported();
+            write_response(stream, response)?;
+            return Ok(false);
+        },
+        Err(ParseError::UnsupportedMethod) => {
+            warn!("Unsupported request method from {}", peer_addr);
+            let response = Response::not_implemented();
+            reject_unread_body(stream, response)?;
+            return Ok(false);
+        },
+        Err(ParseError::UnsupportedTransferCoding) => {
+            warn!("Unsupported transfer coding from {}", peer_addr);
+            let response = Response::not_implemented();
+            reject_unread_body(stream, response)?;
+            return Ok(false);
+        },
+        Err(ParseError::InvalidRequest) => {
+            warn!("Invalid request from {}", peer_addr);
+            let response = Response::bad_request("Invalid request format");
+            write_response(stream, response)?;
+            return Ok(false);
         },
         Err(ParseError::IoError(e)) => {
             if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut {
@@ -463,46 +1262,372 @@ fn handle_connection(mut stream: TcpStream, state: &ServerState, middleware: &[B
         }
     };
     
-    let mut response = {
-        let routes = state.routes.read().unwrap();
-        let key = (request.method.clone(), request.path.clone());
-        
-        if routes.contains_key(&key) {
-            routes[&key](&request, state)
-        } else if routes.keys().any(|(_, p)| p == &request.path) {
-            warn!("405 Method Not Allowed: {:?} {}", request.method, request.path);
-            Response::method_not_allowed(&["GET", "POST"])
-        } else {
-            warn!("404 Not Found: {:?} {}", request.method, request.path);
-            Response::not_found()
+    let _slot = match state.try_acquire_slot(peer_addr.ip()) {
+        Some(slot) => slot,
+        None => {
+            warn!("Too many concurrent requests from {}", peer_addr.ip());
+            let response = Response::too_many_requests();
+            write_response(stream, response)?;
+            return Ok(false);
         }
     };
-    
-    // Process middleware
+
+    // A panic anywhere from the first process() to the last after() still gets the client an
+    // answer, and the connection is closed since shared state may have been left half-updated
+    let mut entered = 0;
+    let mut response = panic::catch_unwind(AssertUnwindSafe(|| respond(&mut request, peer_addr, state, middleware, &mut entered)))
+        .unwrap_or_else(|payload| {
+            error!("Handling {:?} {} panicked: {}", request.method, request.path, panic_message(payload.as_ref()));
+            Response::internal_server_error()
+        });
+
+    // HEAD responses keep the GET headers (including Content-Length) but carry no body
+    if request.method == Method::HEAD {
+        response.body.clear();
+        response.stream = None;
+    }
+
+    // A body the handler never read (lazy_body) is still on the wire ahead of the next request.
+    // A chunked HTTP/1.0 request may have come through an intermediary that framed it
+    // differently, so its connection is not trusted with another request (RFC 7230 3.3.3).
+    let keep_alive = !state.shutting_down()
+        && state.config.keep_alive_timeout_secs > 0
+        && served < state.config.max_requests_per_connection
+        && request.wants_keep_alive()
+        && !request.has_unread_body()
+        && !(request.version == "HTTP/1.0" && request.headers.contains_key("Transfer-Encoding"))
+        && !response.forces_close();
+    response.headers.retain(|name, _| !name.eq_ignore_ascii_case("Connection"));
+    if keep_alive {
+        response.headers.insert("Connection".to_string(), "keep-alive".to_string());
+        response.headers.insert(
+            "Keep-Alive".to_string(),
+            format!("timeout={}, max={}", state.config.keep_alive_timeout_secs, state.config.max_requests_per_connection - served),
+        );
+    } else {
+        response.headers.insert("Connection".to_string(), "close".to_string());
+    }
+
+    let status = response.status_code;
+    let body_bytes = write_response(stream, response)?;
+    let sent = Sent { status, body_bytes };
+    for m in middleware[..entered].iter().rev() {
+        m.completed(&request, &sent);
+    }
+
+    trace!("Completed request handling for {}", peer_addr);
+    Ok(keep_alive)
+}
+
+// Runs the middleware, routing and response hooks for a parsed request. `entered` counts the
+// middleware whose process() ran, which are the ones whose completed() runs later.
+fn respond(
+    request: &mut Request,
+    peer_addr: SocketAddr,
+    state: &ServerState,
+    middleware: &[Box<dyn Middleware>],
+    entered: &mut usize,
+) -> Response {
+    // Onion model: process() runs in order before routing and the first middleware to return a
+    // response short-circuits the rest and the handler; after() then runs in reverse order over
+    // every middleware whose process() ran, so the first middleware sees the final response.
+    let mut short_circuit = None;
     for m in middleware {
-        if let Some(m_response) = m.process(&mut request) {
-            response = m_response;
+        *entered += 1;
+        short_circuit = m.process(request);
+        if short_circuit.is_some() {
+            break;
         }
     }
 
-    // Process after middleware
-    for m in middleware {
-        m.after(&request, &mut response);
+    // Checked after process() so a rewritten path cannot reach the metrics unauthenticated
+    let mut response = short_circuit.unwrap_or_else(|| {
+        let denied = METRICS_PATHS.contains(&request.path.as_str())
+            .then(|| check_metrics_auth(request, peer_addr.ip(), &state.config.metrics_auth))
+            .flatten();
+        match denied {
+            Some(response) => {
+                warn!("Unauthorized metrics request for {} from {}", request.path, peer_addr);
+                response
+            }
+            None => dispatch(request, state),
+        }
+    });
+
+    let status = response.status_code;
+    for (statuses, interceptor) in &state.status_interceptors {
+        if statuses.contains(&status) {
+            interceptor(request, &mut response);
+        }
     }
 
-    // Send the response 
-    write_response_with_retry(&mut stream, &response.to_bytes())?;
-    
-    trace!("Completed request handling for {}", peer_addr);
+    for m in middleware[..*entered].iter().rev() {
+        m.after(request, &mut response);
+    }
+
+    // Headers piling up (e.g. a middleware appending on every call) is a bug, not something
+    // to pass on to a client or proxy that may cut the head short
+    let header_count = response.header_lines().count();
+    let header_size: usize = response.header_lines().map(|(name, value)| name.len() + value.len() + 4).sum();
+    if header_count > state.config.max_response_headers || header_size > state.config.max_response_header_size {
+        error!("Response to {:?} {} has {} headers totalling {} bytes, over the configured limit; sending 500 instead",
+            request.method, request.path, header_count, header_size);
+        response = Response::internal_server_error();
+    }
+
+    response
+}
+
+fn dispatch(request: &mut Request, state: &ServerState) -> Response {
+    let routes = state.routes.read().unwrap();
+    let allowed = routes.allowed_methods(&request.path);
+
+    if let Some((route, handler, params)) = routes.find(&request.method, &request.path) {
+        request.params = params;
+        if request.method == Method::GET && state.coalesced_routes.contains(route) {
+            let key = format!("GET {}", request.target());
+            state.single_flight.run(&key, || handler(request, state))
+        } else {
+            handler(request, state)
+        }
+    } else if let Some(proxy) = state.proxy_for(&request.path) {
+        proxy.forward(request)
+    } else if let Some((files, file)) = state.static_files.as_ref()
+        .filter(|_| allowed.is_empty())
+        .and_then(|files| files.resolve(&request.path).map(|file| (files, file)))
+    {
+        files.serve(&request.method, &file)
+    } else if !allowed.is_empty() && request.method == Method::OPTIONS {
+        let mut response = Response::no_content();
+        response.headers.insert("Allow".to_string(), allow_header(&allowed));
+        response
+    } else if !allowed.is_empty() {
+        warn!("405 Method Not Allowed: {:?} {}", request.method, request.path);
+        Response::method_not_allowed(&allowed_strs(&allowed))
+    } else {
+        warn!("404 Not Found: {:?} {}", request.method, request.path);
+        match &state.not_found_handler {
+            Some(handler) => handler(request),
+            None => Response::not_found(),
+        }
+    }
+}
+
+// A request without Content-Length or Transfer-Encoding has no body as far as framing goes, so
+// any bytes after its head are the next request. require_length answers 411 up front for
+// methods where that usually means a lost payload, except on routes that take no body.
+fn validate_body_policy(request: &Request, policy: &BodyPolicyConfig, bodiless: bool) -> Option<Response> {
+    let method = request.method.as_str();
+    let has_length = request.headers.contains_key("Content-Length");
+    let has_body = request.content_length().is_some_and(|length| length > 0)
+        || request.headers.contains_key("Transfer-Encoding");
+
+    if BodyPolicyConfig::applies(&policy.forbid_body, method) && has_body {
+        return Some(Response::bad_request(&format!("A request body is not allowed for {}", method)));
+    }
+    if BodyPolicyConfig::applies(&policy.require_length, method)
+        && !bodiless
+        && !has_length
+        && !request.headers.contains_key("Transfer-Encoding")
+    {
+        return Some(Response::length_required());
+    }
+    if BodyPolicyConfig::applies(&policy.require_content_type, method)
+        && (has_body || !bodiless)
+        && request.header("Content-Type").is_none()
+    {
+        return Some(Response::bad_request("Missing Content-Type header"));
+    }
+    None
+}
+
+// Bodyless requests pass; otherwise the media type, without parameters such as charset,
+// must be on the route's allowlist if it has one
+fn content_type_accepted(request: &Request, allowlists: &ContentTypeAllowlist) -> bool {
+    let Some(allowed) = allowlists.get(&(request.method.clone(), request.path.clone())) else {
+        return true;
+    };
+    let has_body = request.content_length().is_some_and(|length| length > 0)
+        || request.headers.contains_key("Transfer-Encoding");
+    if !has_body {
+        return true;
+    }
+    request.header("Content-Type")
+        .map(|value| value.split(';').next().unwrap_or("").trim())
+        .is_some_and(|media_type| allowed.iter().any(|a| a.eq_ignore_ascii_case(media_type)))
+}
+
+fn check_metrics_auth(request: &Request, ip: IpAddr, auth: &MetricsAuthConfig) -> Option<Response> {
+    if !auth.allowed_ips.is_empty() && !auth.allowed_ips.contains(&ip) {
+        return Some(Response::forbidden());
+    }
+    if let Some(token) = &auth.token {
+        if !bearer_token_matches(request, token) {
+            return Some(Response::unauthorized());
+        }
+    }
+    None
+}
+
+// Admin routes are only registered with a token set, so a missing token here means deny
+fn check_admin_auth(request: &Request, config: &Config) -> Option<Response> {
+    match &config.admin_token {
+        Some(token) if bearer_token_matches(request, token) => None,
+        _ => Some(Response::unauthorized()),
+    }
+}
+
+// The comparison takes the same time however much of a guessed token is right, so the token
+// cannot be recovered byte by byte from response timings; only its length can be told apart
+fn bearer_token_matches(request: &Request, expected: &str) -> bool {
+    bearer_token(request).is_some_and(|token| {
+        token.len() == expected.len()
+            && token.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    })
+}
+
+// Header names and the auth scheme are both case-insensitive
+fn bearer_token(request: &Request) -> Option<&str> {
+    let value = request.headers.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("Authorization"))
+        .map(|(_, value)| value.as_str())?;
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("Bearer").then(|| token.trim_start())
+}
+
+fn decline_continue(request: &Request, state: &ServerState, limits: &ParseLimits) -> Option<Response> {
+    if request.content_length().is_some_and(|length| length > limits.max_body_size) {
+        return Some(Response::payload_too_large());
+    }
+
+    let routes = state.routes.read().unwrap();
+    let allowed = routes.allowed_methods(&request.path);
+    let static_file = allowed.is_empty()
+        && state.static_files.as_ref().is_some_and(|files| files.resolve(&request.path).is_some());
+    if allowed.contains(&request.method) || state.proxy_for(&request.path).is_some() {
+        None
+    } else if static_file {
+        // Mirrors StaticFiles::serve, which only reads files for GET and HEAD
+        let readable = matches!(request.method, Method::GET | Method::HEAD | Method::OPTIONS);
+        (!readable).then(|| Response::method_not_allowed(&["GET", "HEAD"]))
+    } else if !allowed.is_empty() {
+        Some(Response::method_not_allowed(&allowed_strs(&allowed)))
+    } else {
+        Some(Response::not_found())
+    }
+}
+
+fn allowed_strs(methods: &[Method]) -> Vec<&'static str> {
+    methods.iter().map(Method::as_str).collect()
+}
+
+fn allow_header(methods: &[Method]) -> String {
+    allowed_strs(methods).join(", ")
+}
+
+// Sends a response to a request whose body was never read. Closing right away with unread
+// data makes the kernel reset the connection, which can discard the response before the
+// client reads it, so half-close and drain (bounded by DRAIN_TIMEOUT and DRAIN_LIMIT) first.
+fn reject_unread_body(stream: &mut TcpStream, response: Response) -> io::Result<()> {
+    reject_unread_body_within(stream, response, DRAIN_TIMEOUT)
+}
+
+fn reject_unread_body_within(stream: &mut TcpStream, response: Response, drain_timeout: Duration) -> io::Result<()> {
+    write_response(stream, response)?;
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_read_timeout(Some(drain_timeout))?;
+
+    let deadline = Instant::now() + drain_timeout;
+    let mut buffer = [0; 8192];
+    let mut drained = 0;
+    while drained < DRAIN_LIMIT && Instant::now() < deadline {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => drained += n,
+            Err(_) => break,
+        }
+    }
+    trace!("Drained {} unread request bytes before closing", drained);
     Ok(())
 }
 
-fn write_response_with_retry(stream: &mut TcpStream, response: &[u8]) -> io::Result<()> {
+// Returns the number of bytes written after the head
+fn write_response(stream: &mut TcpStream, mut response: Response) -> io::Result<u64> {
+    let Some(producer) = response.stream.take() else {
+        write_response_with_retry(stream, &response.head_bytes(), &response.body)?;
+        return Ok(response.body.len() as u64);
+    };
+    let chunked = response.headers.contains_key("Transfer-Encoding");
+    let transforms = response.take_transforms();
+    // Producer writes collect here until they call flush() or the buffer fills, so a burst of
+    // small writes costs one syscall rather than one each. The head waits in the buffer too, so
+    // a producer or body transform that panics before anything was sent can still get a 500.
+    let head = response.head_bytes();
+    let mut buffered = BufWriter::with_capacity(STREAM_WRITE_BUFFER_SIZE, RetryingWriter { stream, sent: 0 });
+    let written = panic::catch_unwind(AssertUnwindSafe(|| {
+        buffered.write_all(&head)?;
+        let writer = if chunked {
+            ChunkedWriter::new(&mut buffered)
+        } else {
+            ChunkedWriter::identity(&mut buffered)
+        };
+        let mut writer = writer.with_transforms(transforms);
+        producer(&mut writer)?;
+        writer.finish()
+    }));
+
+    match written {
+        Ok(result) => {
+            result?;
+            buffered.flush()?;
+            Ok((buffered.get_ref().sent - head.len()) as u64)
+        }
+        Err(payload) => {
+            error!("Writing the {} response body panicked: {}", response.status_code, panic_message(payload.as_ref()));
+            // Whatever is still buffered is dropped rather than flushed
+            let (writer, _unsent) = buffered.into_parts();
+            if writer.sent == 0 {
+                let error_response = Response::internal_server_error();
+                write_response_with_retry(writer.stream, &error_response.head_bytes(), &error_response.body)?;
+            }
+            // Part of the response may already be out, so the connection cannot be reused
+            Err(io::Error::other("response body panicked"))
+        }
+    }
+}
+
+// Adapts the retrying writer for streaming bodies, counting what reached the socket
+struct RetryingWriter<'a> {
+    stream: &'a mut TcpStream,
+    sent: usize,
+}
+
+impl Write for RetryingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write_response_with_retry(self.stream, buf, &[])?;
+        self.sent += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        write_response_with_retry(self.stream, &[], &[])
+    }
+}
+
+// Writes head and body with vectored writes so the body is never copied into a combined buffer
+fn write_response_with_retry(stream: &mut TcpStream, head: &[u8], body: &[u8]) -> io::Result<()> {
     let mut retries = 0;
     let mut written = 0;
+    let total = head.len() + body.len();
     
-    while written < response.len() {
-        match stream.write(&response[written..]) {
+    while written < total {
+        let result = if written < head.len() {
+            stream.write_vectored(&[IoSlice::new(&head[written..]), IoSlice::new(body)])
+        } else {
+            stream.write(&body[written - head.len()..])
+        };
+        match result {
+            Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "failed to write response")),
             Ok(n) => {
                 written += n;
                 retries = 0; // Reset retry counter on successful write
@@ -537,3 +1662,909 @@ fn write_response_with_retry(stream: &mut TcpStream, response: &[u8]) -> io::Res
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::thread;
+
+    fn test_config() -> Config {
+        Config { host: "127.0.0.1".to_string(), port: 0, workers: 2, ..Config::default() }
+    }
+
+    // Serves on an ephemeral port in the background for the rest of the test run
+    fn start(server: Server) -> SocketAddr {
+        start_shared(server).1
+    }
+
+    fn start_shared(server: Server) -> (Arc<Server>, SocketAddr) {
+        let addr = server.listener.local_addr().unwrap();
+        let server = Arc::new(server);
+        let running = Arc::clone(&server);
+        thread::spawn(move || running.run());
+        (server, addr)
+    }
+
+    fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        BufReader::new(stream)
+    }
+
+    fn send(conn: &mut BufReader<TcpStream>, raw: &str) {
+        conn.get_mut().write_all(raw.as_bytes()).unwrap();
+    }
+
+    struct TestResponse {
+        status: u16,
+        // Names lowercased
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    impl TestResponse {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+        }
+    }
+
+    fn read_line(conn: &mut BufReader<TcpStream>) -> Option<String> {
+        let mut line = String::new();
+        match conn.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line.trim_end_matches(['\r', '\n']).to_string()),
+        }
+    }
+
+    // None once the server has closed the connection
+    fn read_response(conn: &mut BufReader<TcpStream>, head_request: bool) -> Option<TestResponse> {
+        let status_line = read_line(conn)?;
+        let status = status_line.split(' ').nth(1)?.parse().ok()?;
+        let mut headers = HashMap::new();
+        loop {
+            let line = read_line(conn)?;
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':')?;
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+
+        let mut body = Vec::new();
+        if head_request || (100..200).contains(&status) || status == 204 || status == 304 {
+            // No body
+        } else if headers.get("transfer-encoding").is_some_and(|v| v.contains("chunked")) {
+            body = read_chunked(conn)?;
+        } else if let Some(length) = headers.get("content-length") {
+            body.resize(length.parse().ok()?, 0);
+            conn.read_exact(&mut body).ok()?;
+        } else {
+            conn.read_to_end(&mut body).ok()?;
+        }
+        Some(TestResponse { status, headers, body })
+    }
+
+    fn read_chunked(conn: &mut BufReader<TcpStream>) -> Option<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let size = usize::from_str_radix(read_line(conn)?.split(';').next()?, 16).ok()?;
+            if size == 0 {
+                while !read_line(conn)?.is_empty() {}
+                return Some(body);
+            }
+            let mut chunk = vec![0; size + 2];
+            conn.read_exact(&mut chunk).ok()?;
+            body.extend_from_slice(&chunk[..size]);
+        }
+    }
+
+    fn closed(conn: &mut BufReader<TcpStream>) -> bool {
+        matches!(conn.read(&mut [0; 1]), Ok(0) | Err(_))
+    }
+
+    #[test]
+    fn smuggled_framing_is_rejected_and_closed() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /echo HTTP/1.1\r\nHost: x\r\ntransfer-encoding: chunked\r\nContent-Length: 6\r\n\r\n0\r\n\r\nGET /stats HTTP/1.1\r\nHost: x\r\n\r\n");
+        let response = read_response(&mut conn, false).unwrap();
+        assert_eq!(response.status, 400);
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(closed(&mut conn));
+    }
+
+    #[test]
+    fn serves_requests_up_to_the_per_connection_cap() {
+        let config = Config { max_requests_per_connection: 3, ..test_config() };
+        let addr = start(Server::new(&config).unwrap());
+        let mut conn = connect(addr);
+        for served in 1..=3 {
+            send(&mut conn, "GET /health HTTP/1.1\r\nHost: x\r\n\r\n");
+            let response = read_response(&mut conn, false).unwrap();
+            assert_eq!(response.status, 200);
+            if served < 3 {
+                assert_eq!(response.header("Connection"), Some("keep-alive"));
+                assert_eq!(response.header("Keep-Alive"), Some(format!("timeout=5, max={}", 3 - served).as_str()));
+            } else {
+                assert_eq!(response.header("Connection"), Some("close"));
+            }
+        }
+        assert!(closed(&mut conn));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nfirstPOST /echo HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\nsecond");
+        assert_eq!(read_response(&mut conn, false).unwrap().body, b"first");
+        assert_eq!(read_response(&mut conn, false).unwrap().body, b"second");
+    }
+
+    #[test]
+    fn idle_connections_close_after_the_keep_alive_timeout() {
+        let config = Config { keep_alive_timeout_secs: 1, ..test_config() };
+        let addr = start(Server::new(&config).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "GET /health HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().header("Connection"), Some("keep-alive"));
+
+        let idle_since = Instant::now();
+        assert!(closed(&mut conn));
+        let idled = idle_since.elapsed();
+        assert!(idled >= Duration::from_millis(900) && idled < Duration::from_secs(4), "{:?}", idled);
+    }
+
+    #[test]
+    fn close_and_http10_requests_are_not_kept_alive() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        for request in [
+            "GET /health HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n",
+            "GET /health HTTP/1.0\r\n\r\n",
+            "POST /echo HTTP/1.0\r\nConnection: keep-alive\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhi\r\n0\r\n\r\n",
+        ] {
+            let mut conn = connect(addr);
+            send(&mut conn, request);
+            assert_eq!(read_response(&mut conn, false).unwrap().header("Connection"), Some("close"), "{}", request);
+            assert!(closed(&mut conn));
+        }
+    }
+
+    #[test]
+    fn metrics_token_is_checked_whatever_the_header_case() {
+        let mut config = test_config();
+        config.metrics_auth.token = Some("s3cret".to_string());
+        let addr = start(Server::new(&config).unwrap());
+        for (authorization, status) in [
+            ("", 401),
+            ("Authorization: Bearer wrong!\r\n", 401),
+            ("Authorization: Bearer s3cre\r\n", 401),
+            ("Authorization: Basic s3cret\r\n", 401),
+            ("Authorization: Bearer s3cret\r\n", 200),
+            ("authorization: bearer s3cret\r\n", 200),
+            ("AUTHORIZATION: Bearer s3cret\r\n", 200),
+        ] {
+            let mut conn = connect(addr);
+            send(&mut conn, &format!("GET /stats HTTP/1.1\r\nHost: x\r\n{}Connection: close\r\n\r\n", authorization));
+            assert_eq!(read_response(&mut conn, false).unwrap().status, status, "{:?}", authorization);
+        }
+    }
+
+    #[test]
+    fn metrics_ip_allowlist_is_enforced() {
+        let mut config = test_config();
+        config.metrics_auth.allowed_ips = vec!["10.0.0.1".parse().unwrap()];
+        let addr = start(Server::new(&config).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "GET /stats HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 403);
+        send(&mut conn, "GET /health HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 200);
+    }
+
+    #[test]
+    fn admin_routes_require_the_admin_token() {
+        let config = Config { admin_token: Some("adm1n".to_string()), access_log_buffer: Some(10), ..test_config() };
+        let addr = start(Server::new(&config).unwrap());
+        for (authorization, status) in [
+            ("", 401),
+            ("Authorization: Bearer adm1\r\n", 401),
+            ("Authorization: Bearer adm1nx\r\n", 401),
+            ("authorization: Bearer adm1n\r\n", 200),
+            ("Authorization: BEARER adm1n\r\n", 200),
+        ] {
+            let mut conn = connect(addr);
+            send(&mut conn, &format!("GET /admin/logs HTTP/1.1\r\nHost: x\r\n{}Connection: close\r\n\r\n", authorization));
+            assert_eq!(read_response(&mut conn, false).unwrap().status, status, "{:?}", authorization);
+        }
+    }
+
+    fn with_rewrites(server: Server, rules: &[(&str, &str, Option<u16>)]) -> Server {
+        let rules = rules.iter()
+            .map(|(from, to, redirect)| crate::config::RewriteRule { from: from.to_string(), to: to.to_string(), redirect: *redirect })
+            .collect();
+        server.with_middleware(Box::new(crate::middleware::RewriteMiddleware::new(rules)))
+    }
+
+    #[test]
+    fn checks_run_on_the_rewritten_path() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::POST, "/upload", |req, _state| Response::ok("text/plain", req.load_body().unwrap_or_default()))
+            .with_accepted_content_types(Method::POST, "/upload", &["text/plain"]);
+        let addr = start(with_rewrites(server, &[("/v1/*", "/$1", None)]));
+
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /v1/upload HTTP/1.1\r\nHost: x\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n{}");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 415);
+
+        // 100-continue is granted for the route the rewrite leads to
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /v1/upload HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nExpect: 100-continue\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 100);
+        send(&mut conn, "hi");
+        let response = read_response(&mut conn, false).unwrap();
+        assert_eq!((response.status, response.body), (200, b"hi".to_vec()));
+    }
+
+    #[test]
+    fn redirects_answer_before_route_checks() {
+        let addr = start(with_rewrites(Server::new(&test_config()).unwrap(), &[("/old/*", "/new/$1", Some(308))]));
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /old/form HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 2\r\nExpect: 100-continue\r\n\r\n");
+        let mut response = read_response(&mut conn, false).unwrap();
+        if response.status == 100 {
+            send(&mut conn, "hi");
+            response = read_response(&mut conn, false).unwrap();
+        }
+        assert_eq!(response.status, 308);
+        assert_eq!(response.header("Location"), Some("/new/form"));
+    }
+
+    #[test]
+    fn continue_is_granted_for_static_files() {
+        let dir = std::env::temp_dir().join(format!("web-server-test-static-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("page.html"), "<p>hi</p>").unwrap();
+        let config = Config { static_dir: Some(dir.to_string_lossy().into_owned()), ..test_config() };
+        let addr = start(Server::new(&config).unwrap());
+
+        let mut conn = connect(addr);
+        send(&mut conn, "GET /page.html HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\nExpect: 100-continue\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 100);
+        send(&mut conn, "hi");
+        assert_eq!(read_response(&mut conn, false).unwrap().body, b"<p>hi</p>");
+
+        let mut conn = connect(addr);
+        send(&mut conn, "PUT /page.html HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\nExpect: 100-continue\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 405);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct PanicInAfter;
+
+    impl Middleware for PanicInAfter {
+        fn process(&self, _request: &mut Request) -> Option<Response> {
+            None
+        }
+
+        fn after(&self, request: &Request, _response: &mut Response) {
+            if request.path == "/after" {
+                panic!("after hook failed");
+            }
+        }
+    }
+
+    fn get_status(addr: SocketAddr, path: &str) -> (u16, bool) {
+        let mut conn = connect(addr);
+        send(&mut conn, &format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path));
+        let response = read_response(&mut conn, false).unwrap();
+        (response.status, response.header("Connection") == Some("close") && closed(&mut conn))
+    }
+
+    #[test]
+    fn panics_anywhere_in_the_pipeline_answer_500() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/handler", |_req, _state| panic!("handler failed"))
+            .route(Method::GET, "/intercepted", |_req, _state| Response::new(418, "I'm a teapot", "text/plain", Vec::new()))
+            .route(Method::GET, "/stream", |_req, _state| {
+                Response::stream(200, "OK", "text/plain", |writer| {
+                    writer.write_all(b"partial")?;
+                    panic!("producer failed")
+                })
+            })
+            .route(Method::GET, "/transform", |_req, _state| {
+                let mut response = Response::ok("text/plain", b"body".to_vec());
+                response.push_transform(|_input: &[u8], _output: &mut Vec<u8>| -> io::Result<()> { panic!("transform failed") });
+                response
+            })
+            .with_status_interceptor(418, |_req, _response| panic!("interceptor failed"))
+            .with_middleware(Box::new(PanicInAfter));
+        let addr = start(server);
+
+        for path in ["/handler", "/intercepted", "/after", "/stream", "/transform"] {
+            assert_eq!(get_status(addr, path), (500, true), "{}", path);
+        }
+        // The workers survive
+        assert_eq!(get_status(addr, "/health").0, 200);
+    }
+
+    #[test]
+    fn a_panic_after_the_head_was_sent_closes_the_connection() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/flushed", |_req, _state| {
+                Response::stream(200, "OK", "text/plain", |writer| {
+                    writer.write_all(b"partial")?;
+                    writer.flush()?;
+                    panic!("producer failed")
+                })
+            });
+        let addr = start(server);
+        let mut conn = connect(addr);
+        send(&mut conn, "GET /flushed HTTP/1.1\r\nHost: x\r\n\r\n");
+        // The chunked body never ends, so the client can tell it was cut short
+        assert!(read_response(&mut conn, false).is_none());
+    }
+
+    #[test]
+    fn body_methods_without_a_length_get_411() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        for request in [
+            "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\n\r\nlost payload",
+            // Nothing after the head yet; the answer does not depend on timing
+            "PUT /echo HTTP/1.1\r\nHost: x\r\n\r\n",
+        ] {
+            let mut conn = connect(addr);
+            send(&mut conn, request);
+            assert_eq!(read_response(&mut conn, false).unwrap().status, 411, "{}", request);
+        }
+    }
+
+    #[test]
+    fn pipelined_requests_are_not_taken_for_a_body() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 0\r\n\r\nDELETE /health HTTP/1.1\r\nHost: x\r\n\r\nGET /health HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 200);
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 405);
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 200);
+    }
+
+    #[test]
+    fn shutdown_drains_running_and_queued_connections() {
+        let config = Config { workers: 1, ..test_config() };
+        let server = Server::new(&config).unwrap()
+            .route(Method::GET, "/slow", |_req, _state| {
+                thread::sleep(Duration::from_millis(300));
+                Response::ok("text/plain", b"done".to_vec())
+            });
+        let (server, addr) = start_shared(server);
+
+        let mut running = connect(addr);
+        send(&mut running, "GET /slow HTTP/1.1\r\nHost: x\r\n\r\n");
+        thread::sleep(Duration::from_millis(100));
+        let mut queued = connect(addr);
+        send(&mut queued, "GET /health HTTP/1.1\r\nHost: x\r\n\r\n");
+        thread::sleep(Duration::from_millis(100));
+
+        let report = server.shutdown().unwrap();
+        assert_eq!((report.in_flight_at_start, report.completed, report.abandoned), (2, 2, 0));
+        assert!(!report.timed_out);
+
+        let response = read_response(&mut running, false).unwrap();
+        assert_eq!((response.status, response.header("Connection")), (200, Some("close")));
+        assert_eq!(read_response(&mut queued, false).unwrap().status, 503);
+        assert!(matches!(server.shutdown(), Err(ServerError::ShuttingDown)));
+    }
+
+    #[test]
+    fn shutdown_reports_requests_past_the_grace_period() {
+        let config = Config { workers: 1, shutdown_timeout_secs: 0, ..test_config() };
+        let server = Server::new(&config).unwrap()
+            .route(Method::GET, "/slow", |_req, _state| {
+                thread::sleep(Duration::from_millis(500));
+                Response::ok("text/plain", Vec::new())
+            });
+        let (server, addr) = start_shared(server);
+        let mut conn = connect(addr);
+        send(&mut conn, "GET /slow HTTP/1.1\r\nHost: x\r\n\r\n");
+        thread::sleep(Duration::from_millis(100));
+
+        let report = server.shutdown().unwrap();
+        assert!(report.timed_out);
+        assert_eq!((report.in_flight_at_start, report.abandoned), (1, 1));
+    }
+
+    #[test]
+    fn access_log_reports_what_was_written() {
+        let buffer = Arc::new(AccessLogBuffer::new(10));
+        let logging = crate::middleware::LoggingMiddleware::with_format("$path $status $bytes_sent")
+            .with_buffer(Some(Arc::clone(&buffer)));
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/plain", |_req, _state| Response::ok("text/plain", b"hello".to_vec()))
+            .route(Method::GET, "/stream", |_req, _state| {
+                Response::stream(200, "OK", "text/plain", |writer| writer.write_all(b"abc"))
+            })
+            .route(Method::GET, "/transform", |_req, _state| {
+                let mut response = Response::ok("text/plain", b"ab".to_vec());
+                response.push_transform(|input: &[u8], output: &mut Vec<u8>| -> io::Result<()> {
+                    output.extend_from_slice(input);
+                    output.extend_from_slice(input);
+                    Ok(())
+                });
+                response
+            })
+            .with_middleware(Box::new(logging));
+        let addr = start(server);
+
+        let mut conn = connect(addr);
+        for path in ["/plain", "/stream", "/transform"] {
+            // A client-supplied start time has no say in the logged duration
+            send(&mut conn, &format!("GET {} HTTP/1.1\r\nHost: x\r\nx-start-time: 0\r\n\r\n", path));
+            read_response(&mut conn, false).unwrap();
+        }
+        send(&mut conn, "HEAD /health HTTP/1.1\r\nHost: x\r\n\r\n");
+        read_response(&mut conn, true).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while buffer.snapshot().len() < 4 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let entries = buffer.snapshot();
+        let lines: Vec<&str> = entries.iter().map(|e| e.line.as_str()).collect();
+        // Chunked bodies count their framing: `3\r\nabc\r\n0\r\n\r\n` and `4\r\nabab\r\n0\r\n\r\n`
+        assert_eq!(lines, ["/plain 200 5", "/stream 200 13", "/transform 200 14", "/health 200 0"]);
+        assert!(entries.iter().all(|e| e.duration_ms < 1000));
+    }
+
+    fn get(addr: SocketAddr, path: &str) -> TestResponse {
+        let mut conn = connect(addr);
+        send(&mut conn, &format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path));
+        read_response(&mut conn, false).unwrap()
+    }
+
+    #[test]
+    fn base_path_is_stripped_before_routing() {
+        let config = Config { base_path: "/app".to_string(), ..test_config() };
+        let addr = start(Server::new(&config).unwrap());
+
+        let health = get(addr, "/app/health");
+        assert_eq!(health.status, 200);
+        assert_eq!(health.header("Content-Type"), Some("application/json"));
+        assert_eq!(health.header("Cache-Control"), Some("no-cache"));
+        // A proxy that already stripped the prefix still reaches the route
+        assert_eq!(get(addr, "/health").status, 200);
+        // Only whole segments count as the prefix
+        assert_eq!(get(addr, "/apphealth").status, 404);
+
+        for home in ["/app", "/app/"] {
+            let response = get(addr, home);
+            assert_eq!(response.status, 200, "{}", home);
+            let html = String::from_utf8(response.body).unwrap();
+            assert!(html.contains(r#"href="/app/health""#), "{}", home);
+            assert!(html.contains(r#"href="/app/""#), "{}", home);
+        }
+    }
+
+    #[test]
+    fn oversized_query_strings_get_414() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let huge = format!("q={}", "a".repeat(100 * 1024));
+        let many = vec!["p=1"; 50_000].join("&");
+        // Both of these outgrow the head buffer; the rest fit but break the configured limits
+        let over_length = format!("q={}", "a".repeat(5000));
+        let over_count = vec!["p"; 300].join("&");
+        for query in [&huge, &many, &over_length, &over_count] {
+            let mut conn = connect(addr);
+            send(&mut conn, &format!("GET /health?{} HTTP/1.1\r\nHost: x\r\n\r\n", query));
+            let response = read_response(&mut conn, false).unwrap();
+            assert_eq!(response.status, 414, "{} byte query", query.len());
+            assert!(closed(&mut conn));
+        }
+        assert_eq!(get(addr, &format!("/health?{}", vec!["p"; 256].join("&"))).status, 200);
+    }
+
+    // Writes its parts into a pipe from another thread, pausing between them
+    fn piped(parts: &'static [&'static str]) -> io::PipeReader {
+        let (reader, mut writer) = io::pipe().unwrap();
+        thread::spawn(move || {
+            for part in parts {
+                writer.write_all(part.as_bytes()).unwrap();
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+        reader
+    }
+
+    #[test]
+    fn reader_bodies_are_framed_by_whether_the_length_is_known() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/cursor/known", |_req, _state| Response::from_reader(io::Cursor::new(b"cursor body".to_vec()), Some(11)))
+            .route(Method::GET, "/cursor/unknown", |_req, _state| Response::from_reader(io::Cursor::new(b"cursor body".to_vec()), None))
+            // Anything past the declared length is left unread
+            .route(Method::GET, "/cursor/longer", |_req, _state| Response::from_reader(io::Cursor::new(b"cursor body".to_vec()), Some(6)))
+            .route(Method::GET, "/pipe/known", |_req, _state| Response::from_reader(piped(&["from ", "a pipe"]), Some(11)))
+            .route(Method::GET, "/pipe/unknown", |_req, _state| Response::from_reader(piped(&["from ", "a pipe"]), None));
+        let addr = start(server);
+
+        let mut conn = connect(addr);
+        for (path, body, length) in [
+            ("/cursor/known", "cursor body", Some("11")),
+            ("/cursor/unknown", "cursor body", None),
+            ("/cursor/longer", "cursor", Some("6")),
+            ("/pipe/known", "from a pipe", Some("11")),
+            ("/pipe/unknown", "from a pipe", None),
+        ] {
+            send(&mut conn, &format!("GET {} HTTP/1.1\r\nHost: x\r\n\r\n", path));
+            let response = read_response(&mut conn, false).unwrap();
+            assert_eq!(response.body, body.as_bytes(), "{}", path);
+            assert_eq!(response.header("Content-Length"), length, "{}", path);
+            let chunked = response.header("Transfer-Encoding") == Some("chunked");
+            assert_eq!(chunked, length.is_none(), "{}", path);
+            // Either framing leaves the connection usable for the next request
+            assert_eq!(response.header("Connection"), Some("keep-alive"), "{}", path);
+        }
+    }
+
+    #[test]
+    fn crawler_files_are_served_only_when_configured() {
+        let unconfigured = start(Server::new(&test_config()).unwrap());
+        assert_eq!(get(unconfigured, "/robots.txt").status, 404);
+        assert_eq!(get(unconfigured, "/sitemap.xml").status, 404);
+
+        let sitemap = std::env::temp_dir().join(format!("web-server-sitemap-{}.xml", std::process::id()));
+        fs::write(&sitemap, "<urlset/>").unwrap();
+        let config = Config {
+            robots_txt: Some("User-agent: *\nDisallow: /admin".to_string()),
+            sitemap_xml: Some(sitemap.to_str().unwrap().to_string()),
+            ..test_config()
+        };
+        let configured = start(Server::new(&config).unwrap());
+        fs::remove_file(&sitemap).unwrap();
+
+        let robots = get(configured, "/robots.txt");
+        assert_eq!((robots.status, robots.header("Content-Type")), (200, Some("text/plain; charset=utf-8")));
+        assert_eq!(robots.body, b"User-agent: *\nDisallow: /admin");
+        let sitemap = get(configured, "/sitemap.xml");
+        assert_eq!((sitemap.status, sitemap.header("Content-Type")), (200, Some("application/xml")));
+        assert_eq!(sitemap.body, b"<urlset/>");
+    }
+
+    #[test]
+    fn json_stream_sends_items_before_the_last_is_produced() {
+        #[derive(serde::Serialize)]
+        struct Record {
+            id: usize,
+            name: &'static str,
+        }
+
+        const RECORDS: usize = 100_000;
+        let (resume, paused) = std::sync::mpsc::channel::<()>();
+        let paused = Arc::new(Mutex::new(paused));
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/records", move |_req, _state| {
+                let paused = Arc::clone(&paused);
+                Response::json_stream((0..RECORDS).map(move |id| {
+                    // Hold the second half back until the client has seen the first
+                    if id == RECORDS / 2 {
+                        paused.lock().unwrap().recv_timeout(Duration::from_secs(5)).expect("client never saw the first half");
+                    }
+                    Record { id, name: "record" }
+                }))
+            });
+        let addr = start(server);
+
+        let mut conn = connect(addr);
+        send(&mut conn, "GET /records HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(read_line(&mut conn).as_deref(), Some("HTTP/1.1 200 OK"));
+        let mut chunked = false;
+        loop {
+            let line = read_line(&mut conn).unwrap();
+            if line.is_empty() {
+                break;
+            }
+            chunked |= line.eq_ignore_ascii_case("transfer-encoding: chunked");
+        }
+        assert!(chunked);
+        resume.send(()).unwrap();
+
+        let records: Vec<Value> = serde_json::from_slice(&read_chunked(&mut conn).unwrap()).unwrap();
+        assert_eq!(records.len(), RECORDS);
+        assert_eq!(records[RECORDS - 1], json!({ "id": RECORDS - 1, "name": "record" }));
+    }
+
+    #[test]
+    fn path_params_reach_the_handler() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/users/:id/posts/:post", |req, _state| {
+                Response::ok("text/plain", format!("{} {}", req.params["id"], req.params["post"]).into_bytes())
+            });
+        let addr = start(server);
+        assert_eq!(get(addr, "/users/7/posts/99").body, b"7 99");
+        assert_eq!(get(addr, "/users/7/posts").status, 404);
+    }
+
+    #[test]
+    fn coalesced_routes_share_one_handler_run() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let config = Config { workers: 8, ..test_config() };
+        let server = Server::new(&config).unwrap()
+            .route(Method::GET, "/expensive", move |_req, _state| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(300));
+                Response::ok("text/plain", b"computed".to_vec())
+            })
+            .with_coalesced_route("/expensive");
+        let addr = start(server);
+
+        let clients: Vec<_> = (0..6).map(|_| thread::spawn(move || get(addr, "/expensive?x=1").body)).collect();
+        for client in clients {
+            assert_eq!(client.join().unwrap(), b"computed");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // A different query string is a different request
+        get(addr, "/expensive?x=2");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn status_interceptors_run_for_their_statuses() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/broken", |_req, _state| Response::internal_server_error())
+            .route(Method::GET, "/busy", |_req, _state| Response::service_unavailable())
+            .with_status_interceptor(500, |req, response| {
+                response.headers.insert("X-Error-Reference".to_string(), format!("ref-{}", req.path.trim_start_matches('/')));
+            })
+            .with_status_range_interceptor(500..=599, |_req, response| {
+                response.headers.insert("X-Retry-Note".to_string(), "please retry".to_string());
+            });
+        let addr = start(server);
+
+        let broken = get(addr, "/broken");
+        assert_eq!(broken.status, 500);
+        assert_eq!(broken.header("X-Error-Reference"), Some("ref-broken"));
+        assert_eq!(broken.header("X-Retry-Note"), Some("please retry"));
+
+        let busy = get(addr, "/busy");
+        assert_eq!((busy.header("X-Error-Reference"), busy.header("X-Retry-Note")), (None, Some("please retry")));
+        let healthy = get(addr, "/health");
+        assert_eq!((healthy.header("X-Error-Reference"), healthy.header("X-Retry-Note")), (None, None));
+    }
+
+    #[test]
+    fn flushed_stream_data_arrives_before_the_stream_ends() {
+        let (resume, paused) = std::sync::mpsc::channel::<()>();
+        let paused = Arc::new(Mutex::new(paused));
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/progress", move |_req, _state| {
+                let paused = Arc::clone(&paused);
+                Response::stream(200, "OK", "text/plain", move |writer| {
+                    writer.write_all(b"step 1")?;
+                    writer.flush()?;
+                    // Only continues once the client has seen the flushed step
+                    paused.lock().unwrap().recv_timeout(Duration::from_secs(5)).expect("flushed data never arrived");
+                    writer.write_all(b"step 2")
+                })
+            });
+        let addr = start(server);
+
+        let mut conn = connect(addr);
+        send(&mut conn, "GET /progress HTTP/1.1\r\nHost: x\r\n\r\n");
+        while !read_line(&mut conn).unwrap().is_empty() {}
+        assert_eq!(read_line(&mut conn).as_deref(), Some("6"));
+        assert_eq!(read_line(&mut conn).as_deref(), Some("step 1"));
+        resume.send(()).unwrap();
+        assert_eq!(read_chunked(&mut conn).unwrap(), b"step 2");
+    }
+
+    #[test]
+    fn garbage_methods_get_501_instead_of_a_get_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&calls);
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/counted", move |_req, _state| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                Response::ok("text/plain", Vec::new())
+            });
+        let addr = start(server);
+
+        let mut conn = connect(addr);
+        send(&mut conn, "FROBNICATE /counted HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 501);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(get(addr, "/counted").status, 200);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn non_utf8_header_values_get_400() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let mut conn = connect(addr);
+        conn.get_mut().write_all(b"GET /health HTTP/1.1\r\nHost: x\r\nX-Name: caf\xe9\r\n\r\n").unwrap();
+        let response = read_response(&mut conn, false).unwrap();
+        assert_eq!(response.status, 400);
+        assert!(closed(&mut conn));
+    }
+
+    struct Shout;
+
+    impl Middleware for Shout {
+        fn process(&self, _request: &mut Request) -> Option<Response> {
+            None
+        }
+
+        fn after(&self, _request: &Request, response: &mut Response) {
+            response.push_transform(|input: &[u8], output: &mut Vec<u8>| -> io::Result<()> {
+                output.extend(input.iter().map(u8::to_ascii_uppercase));
+                Ok(())
+            });
+            response.push_transform(|input: &[u8], output: &mut Vec<u8>| -> io::Result<()> {
+                output.extend_from_slice(input);
+                output.push(b'!');
+                Ok(())
+            });
+        }
+    }
+
+    #[test]
+    fn middleware_transforms_compose_while_the_body_is_written() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::GET, "/words", |_req, _state| {
+                Response::stream(200, "OK", "text/plain", |writer| {
+                    writer.write_all(b"one")?;
+                    writer.write_all(b"two")
+                })
+            })
+            .with_middleware(Box::new(Shout));
+        let addr = start(server);
+        let response = get(addr, "/words");
+        assert_eq!(response.header("Transfer-Encoding"), Some("chunked"));
+        assert_eq!(response.body, b"ONE!TWO!");
+    }
+
+    #[test]
+    fn connections_past_a_full_queue_get_503() {
+        let config = Config { workers: 1, queue_capacity: Some(1), ..test_config() };
+        let server = Server::new(&config).unwrap()
+            .route(Method::GET, "/slow", |_req, _state| {
+                thread::sleep(Duration::from_millis(300));
+                Response::ok("text/plain", Vec::new())
+            });
+        let addr = start(server);
+
+        let mut running = connect(addr);
+        send(&mut running, "GET /slow HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+        thread::sleep(Duration::from_millis(100));
+        let mut queued = connect(addr);
+        send(&mut queued, "GET /health HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n");
+        thread::sleep(Duration::from_millis(50));
+
+        let mut rejected = connect(addr);
+        send(&mut rejected, "GET /health HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(read_response(&mut rejected, false).unwrap().status, 503);
+        assert_eq!(read_response(&mut running, false).unwrap().status, 200);
+        assert_eq!(read_response(&mut queued, false).unwrap().status, 200);
+    }
+
+    #[test]
+    fn accept_errors_are_classified_by_kind() {
+        for kind in [ErrorKind::WouldBlock, ErrorKind::Interrupted, ErrorKind::ConnectionAborted, ErrorKind::ConnectionReset] {
+            assert_eq!(AcceptFailure::classify(&io::Error::from(kind)), AcceptFailure::Transient, "{:?}", kind);
+        }
+        assert_eq!(AcceptFailure::classify(&io::Error::from(ErrorKind::OutOfMemory)), AcceptFailure::ResourcePressure);
+        assert_eq!(AcceptFailure::classify(&io::Error::from(ErrorKind::InvalidInput)), AcceptFailure::Fatal);
+        assert_eq!(AcceptFailure::classify(&io::Error::other("unexpected")), AcceptFailure::Other);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn accept_errors_are_classified_by_errno() {
+        // EBADF, EINVAL, ENOTSOCK
+        for errno in [9, 22, 88] {
+            assert_eq!(AcceptFailure::classify(&io::Error::from_raw_os_error(errno)), AcceptFailure::Fatal, "errno {}", errno);
+        }
+        // ENOMEM, ENFILE, EMFILE, ENOBUFS
+        for errno in [12, 23, 24, 105] {
+            assert_eq!(AcceptFailure::classify(&io::Error::from_raw_os_error(errno)), AcceptFailure::ResourcePressure, "errno {}", errno);
+        }
+        // ECONNABORTED, EINTR, EAGAIN
+        for errno in [103, 4, 11] {
+            assert_eq!(AcceptFailure::classify(&io::Error::from_raw_os_error(errno)), AcceptFailure::Transient, "errno {}", errno);
+        }
+        // EPERM
+        assert_eq!(AcceptFailure::classify(&io::Error::from_raw_os_error(1)), AcceptFailure::Other);
+    }
+
+    #[test]
+    fn lowercase_expect_is_answered_before_the_body() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /nope HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nexpect: 100-continue\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 404);
+
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\nContent-Length: 5\r\nexpect: 100-continue\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 100);
+        send(&mut conn, "hello");
+        assert_eq!(read_response(&mut conn, false).unwrap().body, b"hello");
+    }
+
+    #[test]
+    fn body_policy_reads_headers_whatever_their_case() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /echo HTTP/1.1\r\nHost: x\r\ncontent-type: text/plain\r\ncontent-length: 2\r\n\r\nhi");
+        let response = read_response(&mut conn, false).unwrap();
+        assert_eq!((response.status, response.body), (200, b"hi".to_vec()));
+    }
+
+    #[test]
+    fn body_policy_is_skipped_when_a_rewrite_answers() {
+        let addr = start(with_rewrites(Server::new(&test_config()).unwrap(), &[("/old/*", "/new/$1", Some(308))]));
+        // No Content-Type, which require_content_type would otherwise refuse for POST
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /old/form HTTP/1.1\r\nHost: x\r\nContent-Length: 2\r\n\r\nhi");
+        let response = read_response(&mut conn, false).unwrap();
+        assert_eq!((response.status, response.header("Location")), (308, Some("/new/form")));
+    }
+
+    #[test]
+    fn content_type_allowlists_read_headers_whatever_their_case() {
+        let server = Server::new(&test_config()).unwrap()
+            .route(Method::POST, "/upload", |req, _state| Response::ok("text/plain", req.load_body().unwrap_or_default()))
+            .with_accepted_content_types(Method::POST, "/upload", &["text/plain"]);
+        let addr = start(server);
+        for (content_type, status) in [("text/plain; charset=utf-8", 200), ("application/json", 415)] {
+            let mut conn = connect(addr);
+            send(&mut conn, &format!("POST /upload HTTP/1.1\r\nHost: x\r\ncontent-type: {}\r\ncontent-length: 2\r\n\r\nhi", content_type));
+            assert_eq!(read_response(&mut conn, false).unwrap().status, status, "{}", content_type);
+        }
+    }
+
+    #[test]
+    fn require_length_is_configurable() {
+        let config = Config {
+            body_policy: crate::config::BodyPolicyConfig { require_length: Vec::new(), ..Default::default() },
+            ..test_config()
+        };
+        let addr = start(Server::new(&config).unwrap());
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /echo HTTP/1.1\r\nHost: x\r\nContent-Type: text/plain\r\n\r\n");
+        let response = read_response(&mut conn, false).unwrap();
+        assert_eq!((response.status, response.body), (200, Vec::new()));
+    }
+
+    #[test]
+    fn bodiless_admin_endpoints_accept_a_bare_post() {
+        let config = Config { admin_token: Some("adm1n".to_string()), ..test_config() };
+        let addr = start(Server::new(&config).unwrap());
+        for request in [
+            // curl -X POST -H 'Authorization: Bearer adm1n'
+            "POST /admin/drain HTTP/1.1\r\nHost: x\r\nUser-Agent: curl/8.5.0\r\nAccept: */*\r\nAuthorization: Bearer adm1n\r\n\r\n",
+            // curl -X POST -d ''
+            "POST /admin/drain HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer adm1n\r\nContent-Length: 0\r\n\r\n",
+        ] {
+            let mut conn = connect(addr);
+            send(&mut conn, request);
+            assert_eq!(read_response(&mut conn, false).unwrap().status, 202, "{}", request);
+        }
+        // Other POST routes keep the policy
+        let mut conn = connect(addr);
+        send(&mut conn, "POST /echo HTTP/1.1\r\nHost: x\r\nAuthorization: Bearer adm1n\r\n\r\n");
+        assert_eq!(read_response(&mut conn, false).unwrap().status, 411);
+    }
+
+    #[test]
+    fn home_page_fills_every_placeholder() {
+        let addr = start(Server::new(&test_config()).unwrap());
+        let html = String::from_utf8(get(addr, "/").body).unwrap();
+        assert!(html.contains("Total Requests") && html.contains("Server Time: "));
+        assert!(!html.contains("{{") && !html.contains("}}"));
+        assert!(html.contains("@keyframes pulse {"));
+
+        let config = Config { expose_metrics_on_home: false, ..test_config() };
+        let addr = start(Server::new(&config).unwrap());
+        assert!(!String::from_utf8(get(addr, "/").body).unwrap().contains("Total Requests"));
+    }
+}
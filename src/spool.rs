@@ -0,0 +1,211 @@
+use std::collections::hash_map::RandomState;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static SPOOL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+// Names that already exist are skipped; running out means something is squatting on them
+const CREATE_ATTEMPTS: usize = 16;
+
+// A request body written to a temp file; the file is removed when this is dropped
+#[derive(Debug)]
+pub struct SpooledFile {
+    path: PathBuf,
+    file: File,
+    len: usize,
+}
+
+impl SpooledFile {
+    fn create() -> io::Result<SpooledFile> {
+        let dir = std::env::temp_dir();
+        SpooledFile::create_first((0..CREATE_ATTEMPTS).map(|_| dir.join(spool_name())))
+    }
+
+    // The temp dir is shared with other users, so the name is unguessable, the file is
+    // readable by this user only, and a name left behind by another process is skipped
+    fn create_first(paths: impl Iterator<Item = PathBuf>) -> io::Result<SpooledFile> {
+        for path in paths {
+            let mut options = OpenOptions::new();
+            options.read(true).write(true).create_new(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            match options.open(&path) {
+                Ok(file) => return Ok(SpooledFile { path, file, len: 0 }),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::AlreadyExists, "no free name for a spooled body"))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // Opens an independent reader positioned at the start of the body
+    pub fn open(&self) -> io::Result<File> {
+        File::open(&self.path)
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+// Keyed by std's random hasher seed, so the name cannot be predicted from the pid and a count
+fn spool_name() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(SPOOL_COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+    format!("web-server-body-{}-{:016x}", process::id(), hasher.finish())
+}
+
+#[derive(Debug)]
+pub enum RequestBody {
+    InMemory(Vec<u8>),
+    Spooled(SpooledFile),
+}
+
+impl Default for RequestBody {
+    fn default() -> Self {
+        RequestBody::InMemory(Vec::new())
+    }
+}
+
+impl RequestBody {
+    pub fn len(&self) -> usize {
+        match self {
+            RequestBody::InMemory(bytes) => bytes.len(),
+            RequestBody::Spooled(file) => file.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Streams the body without loading a spooled file into memory
+    pub fn reader(&self) -> io::Result<Box<dyn Read + '_>> {
+        match self {
+            RequestBody::InMemory(bytes) => Ok(Box::new(bytes.as_slice())),
+            RequestBody::Spooled(file) => Ok(Box::new(file.open()?)),
+        }
+    }
+
+    pub fn to_vec(&self) -> io::Result<Vec<u8>> {
+        match self {
+            RequestBody::InMemory(bytes) => Ok(bytes.clone()),
+            RequestBody::Spooled(file) => {
+                let mut bytes = Vec::with_capacity(file.len());
+                file.open()?.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+// Collects a body in memory and moves it to a temp file once it grows past `threshold`
+pub struct BodyBuffer {
+    memory: Vec<u8>,
+    spooled: Option<SpooledFile>,
+    threshold: Option<usize>,
+}
+
+impl BodyBuffer {
+    pub fn new(capacity: usize, threshold: Option<usize>) -> Self {
+        BodyBuffer {
+            memory: Vec::with_capacity(capacity),
+            spooled: None,
+            threshold,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.spooled.as_ref().map_or(self.memory.len(), SpooledFile::len)
+    }
+
+    pub fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.spooled.is_none() && self.threshold.is_some_and(|t| self.memory.len() + data.len() > t) {
+            let mut file = SpooledFile::create()?;
+            file.file.write_all(&self.memory)?;
+            file.len = self.memory.len();
+            self.memory = Vec::new();
+            self.spooled = Some(file);
+        }
+
+        match &mut self.spooled {
+            Some(file) => {
+                file.file.write_all(data)?;
+                file.len += data.len();
+            }
+            None => self.memory.extend_from_slice(data),
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<RequestBody> {
+        match self.spooled {
+            Some(mut file) => {
+                file.file.flush()?;
+                Ok(RequestBody::Spooled(file))
+            }
+            None => Ok(RequestBody::InMemory(self.memory)),
+        }
+    }
+}
+
+// For decoders that write into any sink, e.g. http::copy_chunked_body
+impl Write for BodyBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        BodyBuffer::write_all(self, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spooled_files_are_private_and_unpredictable() {
+        let first = SpooledFile::create().unwrap();
+        let second = SpooledFile::create().unwrap();
+        assert_ne!(first.path(), second.path());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(first.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let path = first.path().to_path_buf();
+        drop(first);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn names_taken_by_leftover_files_are_skipped() {
+        let dir = std::env::temp_dir();
+        let (taken, free) = (dir.join(spool_name()), dir.join(spool_name()));
+        fs::write(&taken, "left behind").unwrap();
+
+        let spooled = SpooledFile::create_first([taken.clone(), free.clone()].into_iter()).unwrap();
+        assert_eq!(spooled.path(), free);
+        assert!(SpooledFile::create_first([taken.clone()].into_iter()).is_err());
+        assert_eq!(fs::read_to_string(&taken).unwrap(), "left behind");
+        fs::remove_file(&taken).unwrap();
+    }
+}
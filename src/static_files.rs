@@ -0,0 +1,60 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use crate::http::{percent_decode, Method, Response};
+
+const INDEX_FILE: &str = "index.html";
+
+// Serves files under `static_dir` for requests no route handles
+pub struct StaticFiles {
+    root: PathBuf,
+}
+
+impl StaticFiles {
+    pub fn new(dir: &str) -> io::Result<StaticFiles> {
+        Ok(StaticFiles { root: fs::canonicalize(dir)? })
+    }
+
+    // The file a request path names, or None if it does not exist or lies outside the root.
+    // `.`/`..` segments are refused outright, and the canonical path is checked as well so a
+    // symlink inside the root cannot point outside it. Directories serve their index.html.
+    pub fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut candidate = self.root.clone();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            let segment = percent_decode(segment, false).ok()?;
+            if segment == "." || segment == ".." || segment.contains(['/', '\\', '\0']) {
+                return None;
+            }
+            candidate.push(segment);
+        }
+        if candidate.is_dir() {
+            candidate.push(INDEX_FILE);
+        }
+
+        let resolved = fs::canonicalize(&candidate).ok()?;
+        (resolved.starts_with(&self.root) && resolved.is_file()).then_some(resolved)
+    }
+
+    // Only GET and HEAD read files; OPTIONS lists them and anything else is a 405
+    pub fn serve(&self, method: &Method, file: &Path) -> Response {
+        match method {
+            Method::GET | Method::HEAD => {}
+            Method::OPTIONS => {
+                let mut response = Response::no_content();
+                response.headers.insert("Allow".to_string(), "GET, HEAD".to_string());
+                return response;
+            }
+            _ => return Response::method_not_allowed(&["GET", "HEAD"]),
+        }
+
+        let opened = File::open(file).and_then(|f| Ok((f.metadata()?.len(), f)));
+        let (len, file_handle) = match opened {
+            Ok(opened) => opened,
+            Err(_) => return Response::not_found(),
+        };
+        let content_type = mime_guess::from_path(file).first_or_octet_stream();
+        let mut response = Response::from_reader(file_handle, Some(len as usize));
+        response.headers.insert("Content-Type".to_string(), content_type.to_string());
+        response
+    }
+}
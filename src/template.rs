@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+
+// Renders `{{ key }}` placeholders from `context`, HTML-escaping the values.
+// `{{{ key }}}` inserts the value raw. Unknown keys render as an empty string.
+pub fn render(template: &str, context: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after_open = &rest[start..];
+
+        let (raw, open_len, close) = if after_open.starts_with("{{{") {
+            (true, 3, "}}}")
+        } else {
+            (false, 2, "}}")
+        };
+
+        match after_open[open_len..].find(close) {
+            Some(end) => {
+                let key = after_open[open_len..open_len + end].trim();
+                if let Some(value) = context.get(key) {
+                    if raw {
+                        output.push_str(value);
+                    } else {
+                        output.push_str(&html_escape(value));
+                    }
+                }
+                rest = &after_open[open_len + end + close.len()..];
+            }
+            None => {
+                // Unterminated placeholder, keep the remainder as literal text
+                output.push_str(after_open);
+                rest = "";
+            }
+        }
+    }
+
+    output.push_str(rest);
+    output
+}
+
+pub fn html_escape(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#x27;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn escapes_values_by_default() {
+        let context = context(&[("name", "<b>Tom & \"Jerry\"</b>")]);
+        assert_eq!(render("Hi {{ name }}!", &context), "Hi &lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;!");
+        assert_eq!(render("{{name}}", &context), "&lt;b&gt;Tom &amp; &quot;Jerry&quot;&lt;/b&gt;");
+    }
+
+    #[test]
+    fn triple_braces_insert_raw() {
+        let context = context(&[("html", "<em>as is</em>")]);
+        assert_eq!(render("<p>{{{ html }}}</p>", &context), "<p><em>as is</em></p>");
+    }
+
+    #[test]
+    fn unknown_keys_render_empty() {
+        assert_eq!(render("[{{ missing }}][{{{ missing }}}]", &HashMap::new()), "[][]");
+    }
+
+    #[test]
+    fn unterminated_placeholders_stay_literal() {
+        let context = context(&[("a", "1")]);
+        assert_eq!(render("{{ a }} and {{ a", &context), "1 and {{ a");
+        assert_eq!(render("{{{ a }}", &context), "{{{ a }}");
+        // Single braces, as in CSS, are plain text
+        assert_eq!(render("p { color: red; }", &context), "p { color: red; }");
+    }
+}
@@ -1,20 +1,77 @@
+use std::any::Any;
 use std::thread;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
-use std::sync::mpsc;
+use std::sync::mpsc::{self, TrySendError};
 use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::fmt;
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Message>>,
+    sender: Option<JobSender>,
+    // Shutdown waits for this to reach zero, so a job's decrement is Release and reads are
+    // Acquire: seeing zero means every finished job's writes are visible
     active_count: Arc<AtomicUsize>,
+    // Jobs handed to `execute` that no worker has started yet; only an estimate for load
+    // shedding, so Relaxed is enough
+    queued_count: Arc<AtomicUsize>,
+    // Jobs that panicked; the worker that ran them carries on
+    panicked_count: Arc<AtomicUsize>,
+    queue_capacity: Option<usize>,
+    rejection_policy: RejectionPolicy,
+}
+
+// What `execute` does when a bounded queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionPolicy {
+    // Fail with QueueFull so the caller can answer 503
+    #[default]
+    Reject,
+    // Wait for space, which stalls the accept loop
+    Block,
+    // Run the job on the calling thread
+    CallerRuns,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PoolOptions {
+    pub max_jobs: Option<usize>,
+    // None leaves the queue unbounded and the rejection policy unused
+    pub queue_capacity: Option<usize>,
+    pub rejection_policy: RejectionPolicy,
+}
+
+enum JobSender {
+    Unbounded(mpsc::Sender<Message>),
+    Bounded(mpsc::SyncSender<Message>),
+}
+
+impl JobSender {
+    fn send(&self, message: Message) -> Result<(), mpsc::SendError<Message>> {
+        match self {
+            JobSender::Unbounded(sender) => sender.send(message),
+            JobSender::Bounded(sender) => sender.send(message),
+        }
+    }
 }
 
 #[allow(dead_code)]
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    // A recycled worker stores its replacement's handle here before exiting
+    thread: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+}
+
+#[derive(Clone)]
+struct WorkerContext {
+    receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+    active_count: Arc<AtomicUsize>,
+    panicked_count: Arc<AtomicUsize>,
+    max_jobs: Option<usize>,
 }
 
 enum Message {
@@ -28,6 +85,7 @@ type Job = Box<dyn FnOnce() + Send + 'static>;
 pub enum ThreadPoolError {
     InvalidSize,
     JobSendError(String),
+    QueueFull,
 }
 
 impl fmt::Display for ThreadPoolError {
@@ -35,6 +93,7 @@ impl fmt::Display for ThreadPoolError {
         match self {
             ThreadPoolError::InvalidSize => write!(f, "Thread pool size must be greater than 0"),
             ThreadPoolError::JobSendError(msg) => write!(f, "Failed to send job: {}", msg),
+            ThreadPoolError::QueueFull => write!(f, "Job queue is full"),
         }
     }
 }
@@ -43,23 +102,56 @@ impl std::error::Error for ThreadPoolError {}
 
 impl ThreadPool {
     pub fn new(size: usize) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPool::with_max_jobs(size, None)
+    }
+
+    // Workers exit after `max_jobs` jobs and are replaced by a fresh thread; None never recycles
+    pub fn with_max_jobs(size: usize, max_jobs: Option<usize>) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPool::with_options(size, PoolOptions { max_jobs, ..PoolOptions::default() })
+    }
+
+    // At most `queue_limit` jobs wait for a worker; beyond that `execute` returns QueueFull
+    pub fn with_capacity(size: usize, queue_limit: usize) -> Result<ThreadPool, ThreadPoolError> {
+        ThreadPool::with_options(size, PoolOptions {
+            queue_capacity: Some(queue_limit),
+            rejection_policy: RejectionPolicy::Reject,
+            ..PoolOptions::default()
+        })
+    }
+
+    pub fn with_options(size: usize, options: PoolOptions) -> Result<ThreadPool, ThreadPoolError> {
         if size == 0 {
             return Err(ThreadPoolError::InvalidSize);
         }
 
-        let (sender, receiver) = mpsc::channel();
+        let (sender, receiver) = match options.queue_capacity {
+            Some(capacity) => {
+                let (sender, receiver) = mpsc::sync_channel(capacity);
+                (JobSender::Bounded(sender), receiver)
+            }
+            None => {
+                let (sender, receiver) = mpsc::channel();
+                (JobSender::Unbounded(sender), receiver)
+            }
+        };
         let receiver = Arc::new(Mutex::new(receiver));
         let mut workers = Vec::with_capacity(size);
         let active_count = Arc::new(AtomicUsize::new(0));
+        let panicked_count = Arc::new(AtomicUsize::new(0));
+
+        let context = WorkerContext {
+            receiver,
+            active_count: Arc::clone(&active_count),
+            panicked_count: Arc::clone(&panicked_count),
+            max_jobs: options.max_jobs,
+        };
 
         for id in 0..size {
-            match Worker::new(id, Arc::clone(&receiver), Arc::clone(&active_count)) {
+            match Worker::new(id, context.clone()) {
                 Ok(worker) => workers.push(worker),
                 Err(e) => {
-                    for worker in &mut workers {
-                        if let Some(thread) = worker.thread.take() {
-                            let _ = thread.join();
-                        }
+                    for worker in &workers {
+                        worker.join();
                     }
                     return Err(ThreadPoolError::JobSendError(
                         format!("Failed to create worker {}: {}", id, e)
@@ -72,6 +164,10 @@ impl ThreadPool {
             workers,
             sender: Some(sender),
             active_count,
+            queued_count: Arc::new(AtomicUsize::new(0)),
+            panicked_count,
+            queue_capacity: options.queue_capacity,
+            rejection_policy: options.rejection_policy,
         })
     }
 
@@ -79,18 +175,68 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        if let Some(sender) = &self.sender {
-            sender.send(Message::NewJob(job))
-                .map_err(|e| ThreadPoolError::JobSendError(e.to_string()))?;
-            Ok(())
-        } else {
-            Err(ThreadPoolError::JobSendError("Thread pool is shutting down".to_string()))
+        let queued_count = Arc::clone(&self.queued_count);
+        let message = Message::NewJob(Box::new(move || {
+            queued_count.fetch_sub(1, Ordering::Relaxed);
+            f();
+        }));
+
+        self.queued_count.fetch_add(1, Ordering::Relaxed);
+        let result = self.send(message);
+        if result.is_err() {
+            self.queued_count.fetch_sub(1, Ordering::Relaxed);
         }
+        result
+    }
+
+    fn send(&self, message: Message) -> Result<(), ThreadPoolError> {
+        let sender = match &self.sender {
+            Some(JobSender::Bounded(sender)) if self.rejection_policy != RejectionPolicy::Block => sender,
+            Some(sender) => {
+                return sender.send(message)
+                    .map_err(|e| ThreadPoolError::JobSendError(e.to_string()));
+            }
+            None => {
+                return Err(ThreadPoolError::JobSendError("Thread pool is shutting down".to_string()));
+            }
+        };
+
+        match sender.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(Message::NewJob(job))) if self.rejection_policy == RejectionPolicy::CallerRuns => {
+                job();
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => Err(ThreadPoolError::QueueFull),
+            Err(TrySendError::Disconnected(_)) => {
+                Err(ThreadPoolError::JobSendError("All workers have exited".to_string()))
+            }
+        }
+    }
+
+    // True when a full queue makes `execute` return QueueFull
+    pub fn rejects_when_full(&self) -> bool {
+        matches!(self.sender, Some(JobSender::Bounded(_))) && self.rejection_policy == RejectionPolicy::Reject
     }
 
     pub fn active_count(&self) -> usize {
-        self.active_count.load(Ordering::Relaxed)
+        self.active_count.load(Ordering::Acquire)
+    }
+
+    pub fn panicked_count(&self) -> usize {
+        self.panicked_count.load(Ordering::Relaxed)
+    }
+
+    pub fn queued_count(&self) -> usize {
+        self.queued_count.load(Ordering::Relaxed)
+    }
+
+    // True if a new job would start on an idle worker right away or fit in the bounded queue.
+    // Both counts move concurrently, so this is an estimate for load shedding, not a guarantee.
+    pub fn has_capacity(&self) -> bool {
+        let queued = self.queued_count();
+        let idle = self.workers.len().saturating_sub(self.active_count());
+        idle > queued || self.queue_capacity.is_some_and(|capacity| queued < capacity)
     }
 
 }
@@ -103,25 +249,36 @@ impl Drop for ThreadPool {
             }
         }
 
-        for worker in &mut self.workers {
-            if let Some(thread) = worker.thread.take() {
-                let _ = thread.join();
-            }
+        for worker in &self.workers {
+            worker.join();
         }
     }
 }
 
 impl Worker {
-    fn new(
-        id: usize, 
-        receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
-        active_count: Arc<AtomicUsize>,
-    ) -> Result<Worker, String> {
+    fn new(id: usize, context: WorkerContext) -> Result<Worker, String> {
+        let slot = Arc::new(Mutex::new(None));
+        Worker::spawn(id, context, Arc::clone(&slot))?;
+        Ok(Worker {
+            id,
+            thread: slot,
+        })
+    }
+
+    fn spawn(
+        id: usize,
+        context: WorkerContext,
+        slot: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+    ) -> Result<(), String> {
+        let thread_slot = Arc::clone(&slot);
+        // Hold the slot while spawning so a fast recycle can't be overwritten by our own handle
+        let mut guard = slot.lock().map_err(|e| e.to_string())?;
         let thread = thread::Builder::new()
             .name(format!("worker-{}", id))
             .spawn(move || {
+                let mut jobs_run = 0;
                 loop {
-                    let message = match receiver.lock() {
+                    let message = match context.receiver.lock() {
                         Ok(lock) => match lock.recv() {
                             Ok(msg) => msg,
                             Err(_) => break,
@@ -131,9 +288,24 @@ impl Worker {
 
                     match message {
                         Message::NewJob(job) => {
-                            active_count.fetch_add(1, Ordering::Relaxed);
-                            job();
-                            active_count.fetch_sub(1, Ordering::Relaxed);
+                            context.active_count.fetch_add(1, Ordering::AcqRel);
+                            // Without this a panicking job would end the thread and leave it
+                            // counted as active forever
+                            let outcome = panic::catch_unwind(AssertUnwindSafe(job));
+                            context.active_count.fetch_sub(1, Ordering::Release);
+                            if let Err(payload) = outcome {
+                                context.panicked_count.fetch_add(1, Ordering::Relaxed);
+                                error!("Worker {} recovered from a panicking job: {}", id, panic_message(payload.as_ref()));
+                            }
+
+                            jobs_run += 1;
+                            if context.max_jobs.is_some_and(|max| jobs_run >= max) {
+                                debug!("Worker {} recycling after {} jobs", id, jobs_run);
+                                if let Err(e) = Worker::spawn(id, context.clone(), Arc::clone(&thread_slot)) {
+                                    error!("Failed to respawn worker {}: {}", id, e);
+                                }
+                                break;
+                            }
                         }
                         Message::Terminate => {
                             break;
@@ -143,9 +315,120 @@ impl Worker {
             })
             .map_err(|e| e.to_string())?;
 
-        Ok(Worker {
-            id,
-            thread: Some(thread),
-        })
+        *guard = Some(thread);
+        Ok(())
+    }
+
+    fn join(&self) {
+        // Keep joining until no replacement was left behind by a recycling thread
+        loop {
+            let thread = match self.thread.lock() {
+                Ok(mut slot) => slot.take(),
+                Err(_) => None,
+            };
+            match thread {
+                Some(thread) => {
+                    let _ = thread.join();
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    // One worker stuck on a job until the returned sender fires, and a queue of one already full
+    fn saturated(rejection_policy: RejectionPolicy) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::with_options(1, PoolOptions {
+            queue_capacity: Some(1),
+            rejection_policy,
+            ..PoolOptions::default()
+        }).unwrap();
+        let (release, blocked) = mpsc::channel();
+        pool.execute(move || blocked.recv().unwrap()).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.active_count() == 0 {
+            assert!(Instant::now() < deadline, "worker never picked up the job");
+            thread::sleep(Duration::from_millis(1));
+        }
+        pool.execute(|| {}).unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn reject_policy_fails_with_queue_full() {
+        let (pool, release) = saturated(RejectionPolicy::Reject);
+        assert!(pool.rejects_when_full());
+        assert!(!pool.has_capacity());
+        assert!(matches!(pool.execute(|| {}), Err(ThreadPoolError::QueueFull)));
+        assert_eq!(pool.queued_count(), 1);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn block_policy_waits_for_space() {
+        let (pool, release) = saturated(RejectionPolicy::Block);
+        assert!(!pool.rejects_when_full());
+        let pool = Arc::new(pool);
+        let (done_tx, done) = mpsc::channel();
+        let submitter = {
+            let pool = Arc::clone(&pool);
+            thread::spawn(move || {
+                let result = pool.execute(|| {});
+                done_tx.send(()).unwrap();
+                result
+            })
+        };
+        assert!(done.recv_timeout(Duration::from_millis(100)).is_err(), "execute returned while the queue was full");
+
+        release.send(()).unwrap();
+        done.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(submitter.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn caller_runs_policy_runs_the_job_inline() {
+        let (pool, release) = saturated(RejectionPolicy::CallerRuns);
+        assert!(!pool.rejects_when_full());
+        let caller = thread::current().id();
+        let (ran_tx, ran) = mpsc::channel();
+        pool.execute(move || ran_tx.send(thread::current().id()).unwrap()).unwrap();
+        assert_eq!(ran.try_recv().unwrap(), caller);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn with_capacity_fills_then_reports_queue_full() {
+        let pool = ThreadPool::with_capacity(1, 2).unwrap();
+        let (release, blocked) = mpsc::channel();
+        pool.execute(move || blocked.recv().unwrap()).unwrap();
+        while pool.active_count() == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let (ran_tx, ran) = mpsc::channel();
+        for id in 0..2 {
+            let ran_tx = ran_tx.clone();
+            pool.execute(move || ran_tx.send(id).unwrap()).unwrap();
+        }
+        assert_eq!(pool.queued_count(), 2);
+        assert!(matches!(pool.execute(|| {}), Err(ThreadPoolError::QueueFull)));
+        // A rejected job is not counted as queued
+        assert_eq!(pool.queued_count(), 2);
+
+        release.send(()).unwrap();
+        assert_eq!(ran.recv_timeout(Duration::from_secs(5)).unwrap(), 0);
+        assert_eq!(ran.recv_timeout(Duration::from_secs(5)).unwrap(), 1);
+        assert!(pool.execute(|| {}).is_ok());
     }
 }
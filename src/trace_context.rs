@@ -0,0 +1,75 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// W3C Trace Context for one request: the trace it belongs to, the caller's span
+// (if any) and the span this server opens for handling it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub parent_id: Option<String>,
+    pub span_id: String,
+    pub flags: String,
+}
+
+impl TraceContext {
+    // Continues the trace from an incoming `traceparent`, or starts a new sampled one
+    pub fn from_traceparent(header: Option<&str>) -> TraceContext {
+        match header.and_then(parse_traceparent) {
+            Some((trace_id, parent_id, flags)) => TraceContext {
+                trace_id,
+                parent_id: Some(parent_id),
+                span_id: random_hex(8),
+                flags,
+            },
+            None => TraceContext {
+                trace_id: random_hex(16),
+                parent_id: None,
+                span_id: random_hex(8),
+                flags: "01".to_string(),
+            },
+        }
+    }
+
+    // The value to send downstream, with this server's span as the parent
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+// `version-traceid-parentid-flags`; all-zero ids and version ff are invalid
+fn parse_traceparent(value: &str) -> Option<(String, String, String)> {
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [version, trace_id, parent_id, flags, ..] = parts.as_slice() else {
+        return None;
+    };
+    let is_hex = |s: &str, len: usize| s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'));
+
+    if !is_hex(version, 2) || *version == "ff" || (*version == "00" && parts.len() != 4) {
+        return None;
+    }
+    if !is_hex(trace_id, 32) || !is_hex(parent_id, 16) || !is_hex(flags, 2) {
+        return None;
+    }
+    if trace_id.bytes().all(|b| b == b'0') || parent_id.bytes().all(|b| b == b'0') {
+        return None;
+    }
+    Some((trace_id.to_string(), parent_id.to_string(), flags.to_string()))
+}
+
+// Ids only need to be unique, not unpredictable, so mix time and a counter through
+// std's randomly keyed hasher rather than pulling in an RNG
+fn random_hex(bytes: usize) -> String {
+    let mut out = String::with_capacity(bytes * 2);
+    while out.len() < bytes * 2 {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(ID_COUNTER.fetch_add(1, Ordering::Relaxed));
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos()));
+        out.push_str(&format!("{:016x}", hasher.finish()));
+    }
+    out.truncate(bytes * 2);
+    out
+}
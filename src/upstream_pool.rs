@@ -0,0 +1,251 @@
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+use log::{debug, warn};
+use crate::config::UpstreamPoolConfig;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct IdleConnection {
+    stream: TcpStream,
+    idle_since: Instant,
+}
+
+pub struct PooledConnection {
+    pub stream: TcpStream,
+    // Reused connections may have been closed by the upstream since their last check
+    pub reused: bool,
+}
+
+// Keep-alive connections to one upstream, handed out most recently used first. A background
+// thread checks the idle ones every health_check_interval_secs, evicting those the upstream
+// closed or that idled past idle_timeout_secs, and opens new ones up to min_idle.
+pub struct UpstreamPool {
+    host: String,
+    port: u16,
+    config: UpstreamPoolConfig,
+    idle: Mutex<Vec<IdleConnection>>,
+}
+
+impl UpstreamPool {
+    pub fn new(host: &str, port: u16, config: UpstreamPoolConfig) -> Arc<UpstreamPool> {
+        let pool = Arc::new(UpstreamPool {
+            host: host.to_string(),
+            port,
+            config,
+            idle: Mutex::new(Vec::new()),
+        });
+
+        // Holds a Weak so the thread ends once the pool is dropped
+        let weak = Arc::downgrade(&pool);
+        let interval = Duration::from_secs(pool.config.health_check_interval_secs.max(1));
+        let spawned = thread::Builder::new()
+            .name(format!("upstream-pool-{}:{}", host, port))
+            .spawn(move || maintain_loop(weak, interval));
+        if let Err(e) = spawned {
+            warn!("Failed to start health checks for upstream {}:{}: {}", host, port, e);
+        }
+        pool
+    }
+
+    pub fn checkout(&self) -> io::Result<PooledConnection> {
+        loop {
+            let candidate = self.idle.lock().unwrap().pop();
+            match candidate {
+                Some(idle) if self.is_usable(&idle) => {
+                    return Ok(PooledConnection { stream: idle.stream, reused: true });
+                }
+                Some(_) => debug!("Discarding stale connection to {}:{}", self.host, self.port),
+                None => return Ok(PooledConnection { stream: self.connect()?, reused: false }),
+            }
+        }
+    }
+
+    // For a connection whose last response was read completely and that the upstream agreed
+    // to keep open; anything over max_idle is closed
+    pub fn checkin(&self, stream: TcpStream) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.config.max_idle {
+            idle.push(IdleConnection { stream, idle_since: Instant::now() });
+        }
+    }
+
+    pub fn connect(&self) -> io::Result<TcpStream> {
+        let addr = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "upstream host did not resolve"))?;
+        let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        Ok(stream)
+    }
+
+    fn is_usable(&self, idle: &IdleConnection) -> bool {
+        idle.idle_since.elapsed() < Duration::from_secs(self.config.idle_timeout_secs) && is_alive(&idle.stream)
+    }
+
+    fn maintain(&self) {
+        // Probe outside the lock so checkouts are not held up by the sweep
+        let checked: Vec<IdleConnection> = std::mem::take(&mut *self.idle.lock().unwrap());
+        let before = checked.len();
+        let mut kept: Vec<IdleConnection> = checked.into_iter().filter(|idle| self.is_usable(idle)).collect();
+        if kept.len() < before {
+            debug!("Evicted {} idle connections to {}:{}", before - kept.len(), self.host, self.port);
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        // Connections checked in during the sweep are the most recent, so they go on top, and
+        // any excess is closed from the bottom where the longest idle ones are
+        kept.append(&mut idle);
+        let excess = kept.len().saturating_sub(self.config.max_idle);
+        kept.drain(..excess);
+        *idle = kept;
+        let missing = self.config.min_idle.saturating_sub(idle.len());
+        drop(idle);
+
+        for _ in 0..missing {
+            match self.connect() {
+                Ok(stream) => self.checkin(stream),
+                Err(e) => {
+                    debug!("Failed to warm a connection to {}:{}: {}", self.host, self.port, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn maintain_loop(pool: Weak<UpstreamPool>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match pool.upgrade() {
+            Some(pool) => pool.maintain(),
+            None => return,
+        }
+    }
+}
+
+// An idle connection should have nothing to read: EOF or an error means the upstream closed
+// it, and unexpected bytes would corrupt the next response read from it
+fn is_alive(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let alive = matches!(stream.peek(&mut [0; 1]), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_ok() && alive
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn config(max_idle: usize, min_idle: usize, idle_timeout_secs: u64) -> UpstreamPoolConfig {
+        UpstreamPoolConfig { max_idle, min_idle, idle_timeout_secs, health_check_interval_secs: 3600 }
+    }
+
+    // An upstream that keeps every connection open until the test ends
+    fn upstream() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    fn local_port(stream: &TcpStream) -> u16 {
+        stream.local_addr().unwrap().port()
+    }
+
+    #[test]
+    fn reuses_checked_in_connections_most_recent_first() {
+        let (_listener, port) = upstream();
+        let pool = UpstreamPool::new("127.0.0.1", port, config(8, 0, 60));
+        let first = pool.checkout().unwrap();
+        let second = pool.checkout().unwrap();
+        assert!(!first.reused && !second.reused);
+        let (first_port, second_port) = (local_port(&first.stream), local_port(&second.stream));
+        pool.checkin(first.stream);
+        pool.checkin(second.stream);
+
+        let again = pool.checkout().unwrap();
+        assert!(again.reused);
+        assert_eq!(local_port(&again.stream), second_port);
+        assert_eq!(local_port(&pool.checkout().unwrap().stream), first_port);
+    }
+
+    #[test]
+    fn checkin_closes_connections_over_max_idle() {
+        let (_listener, port) = upstream();
+        let pool = UpstreamPool::new("127.0.0.1", port, config(1, 0, 60));
+        let first = pool.checkout().unwrap().stream;
+        let second = pool.checkout().unwrap().stream;
+        pool.checkin(first);
+        pool.checkin(second);
+        assert_eq!(pool.idle.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn discards_connections_the_upstream_closed() {
+        let (listener, port) = upstream();
+        let pool = UpstreamPool::new("127.0.0.1", port, config(8, 0, 60));
+        let stale = pool.checkout().unwrap().stream;
+        let stale_port = local_port(&stale);
+        pool.checkin(stale);
+        drop(listener.accept().unwrap());
+        thread::sleep(Duration::from_millis(50));
+
+        let fresh = pool.checkout().unwrap();
+        assert!(!fresh.reused);
+        assert_ne!(local_port(&fresh.stream), stale_port);
+    }
+
+    #[test]
+    fn maintain_evicts_closed_and_expired_connections() {
+        let (listener, port) = upstream();
+        let pool = UpstreamPool::new("127.0.0.1", port, config(8, 0, 60));
+        let closed = pool.checkout().unwrap().stream;
+        let open = pool.checkout().unwrap().stream;
+        let open_port = local_port(&open);
+        pool.checkin(closed);
+        drop(listener.accept().unwrap());
+        pool.checkin(open);
+        thread::sleep(Duration::from_millis(50));
+
+        pool.maintain();
+        let idle = pool.idle.lock().unwrap();
+        assert_eq!(idle.iter().map(|i| local_port(&i.stream)).collect::<Vec<_>>(), [open_port]);
+        drop(idle);
+
+        let expiring = UpstreamPool::new("127.0.0.1", port, config(8, 0, 0));
+        let stream = expiring.checkout().unwrap().stream;
+        expiring.checkin(stream);
+        expiring.maintain();
+        assert!(expiring.idle.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn maintain_keeps_the_most_recent_connections() {
+        let (_listener, port) = upstream();
+        let pool = UpstreamPool::new("127.0.0.1", port, config(2, 0, 60));
+        let streams: Vec<TcpStream> = (0..3).map(|_| pool.connect().unwrap()).collect();
+        let ports: Vec<u16> = streams.iter().map(local_port).collect();
+        // As if the limit had been lowered or connections were checked in during a sweep
+        pool.idle.lock().unwrap().extend(streams.into_iter().map(|stream| IdleConnection { stream, idle_since: Instant::now() }));
+
+        pool.maintain();
+        let idle = pool.idle.lock().unwrap();
+        assert_eq!(idle.iter().map(|i| local_port(&i.stream)).collect::<Vec<_>>(), ports[1..]);
+    }
+
+    #[test]
+    fn maintain_opens_connections_up_to_min_idle() {
+        let (_listener, port) = upstream();
+        let pool = UpstreamPool::new("127.0.0.1", port, config(8, 3, 60));
+        pool.maintain();
+        assert_eq!(pool.idle.lock().unwrap().len(), 3);
+        assert!(pool.checkout().unwrap().reused);
+    }
+}